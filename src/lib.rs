@@ -1,3 +1,61 @@
 mod utils;
-pub use utils::ast::AST;
-pub use utils::machine::MachineState;
+use std::io;
+pub use utils::analysis::{BasicBlock, Cost, Effect, LoopInfo, ParseStats};
+pub use utils::ast::{
+    FlatOp, ParseError, Position, Span, AST, DEFAULT_MAX_DEPTH, DEFAULT_SENTINEL,
+};
+pub use utils::codegen::CodegenError;
+pub use utils::debugger::{Command, Debugger};
+pub use utils::dialect::Dialect;
+pub use utils::frames::{Frame, FrameRecorder, SharedTape};
+pub use utils::machine::{
+    is_command, LineError, MachineState, Measurement, OutputMode, RunError, Timing, COMMANDS,
+};
+pub use utils::optimizer::{
+    CancelPairs, ClearLoops, CoalesceRuns, DeadLoops, FoldBlocks, OptPass, OptReport, Optimizer,
+    ScanLoops, SetCells,
+};
+pub use utils::output::OutputBuffer;
+pub use utils::program::{Instruction, OptLevel, Program};
+pub use utils::snapshot::Snapshot;
+pub use utils::stepper::{OutputBytes, Stepper, WatchpointHit};
+#[cfg(feature = "mmap")]
+pub use utils::tape::MmapTape;
+pub use utils::tape::Tape;
+pub use utils::token::{tokenize, Token, TokenKind};
+pub use utils::transcript::{IoEvent, Transcript};
+pub use utils::visitor::Visitor;
+
+/// Runs `source` on `input`, returning the output as a string where invalid UTF-8 is replaced.
+pub fn eval(source: &str, input: &str) -> io::Result<String> {
+    let output = AST::from(&mut source.chars()).run_for_output(input.as_bytes())?;
+
+    Ok(String::from_utf8_lossy(&output).into_owned())
+}
+
+/// Canonical formatting of `source`, as given by [`AST::to_pretty_string`]: comments are
+/// dropped and loops are indented. Fails on unbalanced brackets or on loops nested too deep.
+pub fn format_source(source: &str) -> Result<String, ParseError> {
+    Ok(AST::from_bytes(source.as_bytes())?.to_pretty_string())
+}
+
+/// Panics unless running `source` on `input` prints `expected`.
+#[track_caller]
+pub fn assert_output(source: &str, input: &str, expected: &str) {
+    match eval(source, input) {
+        Ok(output) => assert_eq!(output, expected, "unexpected output of {:?}", source),
+        Err(error) => panic!("running {:?} failed: {}", source, error),
+    }
+}
+
+/// Runs `programs` one after the other, like a shell pipeline: each program reads the output of
+/// the previous one, the first reads `initial_input`. Returns the output of the last program.
+pub fn pipeline(programs: &[&AST], initial_input: &[u8]) -> io::Result<Vec<u8>> {
+    let mut data = initial_input.to_vec();
+
+    for program in programs {
+        data = program.run_for_output(&data)?;
+    }
+
+    Ok(data)
+}
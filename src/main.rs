@@ -1,19 +1,126 @@
-use rebf::{MachineState, AST};
+use rebf::{Debugger, Dialect, MachineState, OptLevel, OutputMode, Program, AST};
 use std::env;
 use std::fs;
+use std::io::{self, BufReader};
+use std::process;
+
+/// Contents of `path`, fetched over HTTP(S) when it is a URL.
+fn read_source(path: &str) -> io::Result<Vec<u8>> {
+    #[cfg(feature = "reqwest")]
+    if path.starts_with("http://") || path.starts_with("https://") {
+        return reqwest::blocking::get(path)
+            .and_then(|response| response.error_for_status())
+            .and_then(|response| response.bytes())
+            .map(|body| body.to_vec())
+            .map_err(io::Error::other);
+    }
+    fs::read(path)
+}
 
 fn main() {
     let args: Vec<String> = env::args().collect();
 
-    if args.len() >= 2 {
+    let mut hex_output = false;
+    let mut debug = false;
+    let mut format = false;
+    let mut run_compiled = false;
+    let mut compile_file = None;
+    let mut dump_tape = None;
+    let mut opt_level = None;
+    let mut input_file = None;
+    let mut dialect_file = None;
+    let mut source_file = None;
+    let mut options = args.iter().skip(1);
+    while let Some(arg) = options.next() {
+        match arg.as_ref() {
+            "--hex-output" => hex_output = true,
+            "--debug" => debug = true,
+            "--format" => format = true,
+            "--compile" => compile_file = Some(options.next().expect("Missing compiled file.")),
+            "--run-compiled" => run_compiled = true,
+            "--input" => input_file = Some(options.next().expect("Missing input file.")),
+            "--dialect" => dialect_file = Some(options.next().expect("Missing dialect file.")),
+            "--dump-tape" => dump_tape = Some(options.next().expect("Missing tape dump file.")),
+            "-O0" => opt_level = Some(OptLevel::None),
+            "-O1" => opt_level = Some(OptLevel::Basic),
+            "-O2" => opt_level = Some(OptLevel::Full),
+            _ => source_file = Some(arg),
+        }
+    }
+
+    if let Some(source_file) = source_file {
         let mut machine = MachineState::new();
+        if let Some(input_file) = input_file {
+            let input = fs::File::open(input_file).expect("Input file not found.");
+            machine = machine.with_input(input);
+        }
+        if hex_output {
+            machine = machine.with_output_mode(OutputMode::Hex);
+        }
+
+        if run_compiled {
+            let bytes = read_source(source_file).expect("File not found.");
+            let program = Program::from_bytes(&bytes).expect("Invalid compiled program.");
+            machine.run_program(&program).expect("Execution failed");
+            if let Some(dump_file) = dump_tape {
+                machine.dump_tape(dump_file).expect("Tape dump failed");
+            }
+            return;
+        }
+
+        let instructions = String::from_utf8(read_source(source_file).expect("File not found."))
+            .expect("Program is not valid UTF-8.");
+
+        let source = AST::strip_shebang(&instructions);
+        let dialect =
+            dialect_file.map(|file| Dialect::from_file(file).expect("Invalid dialect file."));
+
+        if format {
+            let standard = match &dialect {
+                Some(dialect) => dialect.translate(source),
+                None => source.to_string(),
+            };
+            match rebf::format_source(&standard) {
+                Ok(formatted) => print!("{}", formatted),
+                Err(error) => {
+                    eprintln!("Invalid program: {}", error);
+                    process::exit(1);
+                }
+            }
+            return;
+        }
+
+        let ast = match &dialect {
+            Some(dialect) => AST::from_dialect(source, dialect),
+            None => AST::from(&mut source.chars()),
+        };
 
-        let instructions = fs::read_to_string(args[1].as_ref() as &str).expect("File not found.");
+        if let Some(compile_file) = compile_file {
+            let program = ast.compile_with(opt_level.unwrap_or(OptLevel::Full));
+            fs::write(compile_file, program.to_bytes()).expect("Could not write compiled file.");
+            return;
+        }
 
-        let ast = AST::from(&mut instructions.chars());
+        if debug {
+            let commands = BufReader::new(io::stdin());
+            Debugger::new(machine.stepper(&ast), commands, io::stdout())
+                .run()
+                .expect("Execution failed");
+        } else if let Some(level) = opt_level {
+            machine
+                .run_program(&ast.compile_with(level))
+                .expect("Execution failed");
+        } else {
+            machine.run(&ast).expect("Execution failed");
+        }
 
-        machine.run(&ast).expect("Execution failed");
+        if let Some(dump_file) = dump_tape {
+            machine.dump_tape(dump_file).expect("Tape dump failed");
+        }
     } else {
-        println!("Usage : {} [SOURCE_FILE]", args[0]);
+        println!(
+            "Usage : {} [--hex-output] [--debug] [--format] [-O0|-O1|-O2] [--input FILE] [--dialect FILE] [--dump-tape FILE] [--compile FILE] [--run-compiled] [SOURCE_FILE]",
+            args[0]
+        );
     }
 }
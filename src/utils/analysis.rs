@@ -0,0 +1,667 @@
+use super::ast::AST;
+use super::machine::{Computation, Direction, MachineState, Operation};
+use super::visitor::Visitor;
+use std::collections::HashMap;
+
+/// Static estimate of the work needed to run a program.
+///
+/// Costs order by boundedness first, then by size, so that sorting programs by cost schedules
+/// the cheap ones first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Cost {
+    /// Loop-free program, running exactly this many operations.
+    Bounded(usize),
+    /// Program with loops, whose bodies are counted once.
+    Unbounded { operations: usize, loops: usize },
+}
+
+impl Cost {
+    pub fn operations(&self) -> usize {
+        match self {
+            Cost::Bounded(operations) | Cost::Unbounded { operations, .. } => *operations,
+        }
+    }
+}
+
+/// Net effect of straight-line code on the tape, relative to the starting cell.
+///
+/// Offsets assume the code never moves left of the first cell, where moves stop.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Effect {
+    pub pointer_offset: isize,
+    /// Amount added to each cell, wrapping, sorted by offset. Unchanged cells are left out.
+    pub deltas: Vec<(isize, u8)>,
+}
+
+/// Net pointer movement, if it doesn't depend on the tape.
+fn net_movement(mut ast: &AST) -> Option<isize> {
+    let mut offset: isize = 0;
+    loop {
+        match ast {
+            AST::Instructions(operations, next) => {
+                for operation in operations {
+                    match operation {
+                        Operation::Move(Direction::Right) => offset += 1,
+                        Operation::Move(Direction::Left) => offset -= 1,
+                        Operation::MoveBy(Direction::Right, count) => offset += *count as isize,
+                        Operation::MoveBy(Direction::Left, count) => offset -= *count as isize,
+                        Operation::Block { net_move, .. } => offset += net_move,
+                        Operation::Scan(_)
+                        | Operation::Move(Direction::Up | Direction::Down)
+                        | Operation::MoveBy(Direction::Up | Direction::Down, _) => return None,
+                        _ => {}
+                    }
+                }
+                ast = next;
+            }
+            AST::Loop(body, next) => {
+                if net_movement(body)? != 0 {
+                    return None;
+                }
+                ast = next;
+            }
+            AST::EOF => return Some(offset),
+        }
+    }
+}
+
+/// Pointer at the end of `ast` and furthest cell reached, starting on cell `start`, if they
+/// don't depend on the tape.
+fn reach(mut ast: &AST, start: usize) -> Option<(usize, usize)> {
+    let (mut pointer, mut furthest) = (start, start);
+    loop {
+        match ast {
+            AST::Instructions(operations, next) => {
+                for operation in operations {
+                    match operation {
+                        Operation::Move(Direction::Right) => pointer += 1,
+                        Operation::Move(Direction::Left) => pointer = pointer.saturating_sub(1),
+                        Operation::MoveBy(Direction::Right, count) => pointer += count,
+                        Operation::MoveBy(Direction::Left, count) => {
+                            pointer = pointer.saturating_sub(*count)
+                        }
+                        Operation::Block { deltas, net_move } => {
+                            let mut offset = 0;
+                            for target in deltas.iter().map(|(target, _)| *target) {
+                                pointer = pointer.saturating_add_signed(target - offset);
+                                furthest = furthest.max(pointer);
+                                offset = target;
+                            }
+                            pointer = pointer.saturating_add_signed(net_move - offset);
+                        }
+                        Operation::Scan(_)
+                        | Operation::Move(Direction::Up | Direction::Down)
+                        | Operation::MoveBy(Direction::Up | Direction::Down, _) => return None,
+                        _ => {}
+                    }
+                    furthest = furthest.max(pointer);
+                }
+                ast = next;
+            }
+            AST::Loop(body, next) => {
+                let (end, body_furthest) = reach(body, pointer)?;
+                if end != pointer {
+                    return None;
+                }
+                furthest = furthest.max(body_furthest);
+                ast = next;
+            }
+            AST::EOF => return Some((pointer, furthest)),
+        }
+    }
+}
+
+/// Straight-line run of operations between loop brackets, see [`AST::basic_blocks`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct BasicBlock {
+    pub operations: Vec<Operation>,
+    /// Number of loops around the block.
+    pub depth: usize,
+    /// Blocks that may run next, as indices: blocks ending with a bracket continue either with
+    /// the loop body or after the loop. The last block of the program has none.
+    pub successors: Vec<usize>,
+}
+
+impl BasicBlock {
+    fn new(depth: usize) -> BasicBlock {
+        BasicBlock {
+            operations: Vec::new(),
+            depth,
+            successors: Vec::new(),
+        }
+    }
+}
+
+/// Appends the blocks of `ast`, starting with a new block.
+fn split_blocks(mut ast: &AST, depth: usize, blocks: &mut Vec<BasicBlock>) {
+    blocks.push(BasicBlock::new(depth));
+    loop {
+        match ast {
+            AST::Instructions(operations, next) => {
+                if let Some(block) = blocks.last_mut() {
+                    block.operations.extend(operations.iter().cloned());
+                }
+                ast = next;
+            }
+            AST::Loop(body, next) => {
+                let entry = blocks.len() - 1;
+                let body_start = blocks.len();
+                split_blocks(body, depth + 1, blocks);
+                let exit = blocks.len() - 1;
+
+                blocks.push(BasicBlock::new(depth));
+                let after = blocks.len() - 1;
+                blocks[entry].successors = vec![body_start, after];
+                blocks[exit].successors = vec![body_start, after];
+                ast = next;
+            }
+            AST::EOF => return,
+        }
+    }
+}
+
+/// Structural summary of a program, see [`AST::stats`].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ParseStats {
+    pub operations: usize,
+    pub loops: usize,
+    pub max_depth: usize,
+    /// Number of operations per command character, as given by [`Operation::value`].
+    pub per_operation: HashMap<char, usize>,
+}
+
+#[derive(Default)]
+struct StatsCollector {
+    stats: ParseStats,
+    depth: usize,
+}
+
+impl Visitor for StatsCollector {
+    fn visit_op(&mut self, operation: &Operation) {
+        self.stats.operations += 1;
+        *self
+            .stats
+            .per_operation
+            .entry(operation.value())
+            .or_insert(0) += 1;
+    }
+
+    fn visit_loop_enter(&mut self, _body: &AST) {
+        self.stats.loops += 1;
+        self.depth += 1;
+        self.stats.max_depth = self.stats.max_depth.max(self.depth);
+    }
+
+    fn visit_loop_exit(&mut self, _body: &AST) {
+        self.depth -= 1;
+    }
+}
+
+#[derive(Default)]
+struct Counter {
+    operations: usize,
+    loops: usize,
+}
+
+impl Visitor for Counter {
+    fn visit_op(&mut self, _operation: &Operation) {
+        self.operations += 1;
+    }
+
+    fn visit_loop_enter(&mut self, _body: &AST) {
+        self.loops += 1;
+    }
+}
+
+/// Loop of a program, see [`AST::loops`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct LoopInfo {
+    /// Source of the loop body, without the brackets.
+    pub body: String,
+    /// Number of loops around this one.
+    pub depth: usize,
+}
+
+#[derive(Default)]
+struct LoopCollector {
+    loops: Vec<LoopInfo>,
+    depth: usize,
+}
+
+impl Visitor for LoopCollector {
+    fn visit_loop_enter(&mut self, body: &AST) {
+        self.loops.push(LoopInfo {
+            body: body.to_string(),
+            depth: self.depth,
+        });
+        self.depth += 1;
+    }
+
+    fn visit_loop_exit(&mut self, _body: &AST) {
+        self.depth -= 1;
+    }
+}
+
+/// Whether a left move was visited.
+#[derive(Default)]
+struct LeftMoves(bool);
+
+impl Visitor for LeftMoves {
+    fn visit_op(&mut self, operation: &Operation) {
+        self.0 |= matches!(
+            operation,
+            Operation::Move(Direction::Left | Direction::Up)
+                | Operation::MoveBy(Direction::Left | Direction::Up, _)
+                | Operation::Scan(Direction::Left | Direction::Up)
+        );
+        if let Operation::Block { deltas, net_move } = operation {
+            let offsets: Vec<isize> = deltas.iter().map(|(target, _)| *target).collect();
+            self.0 |= std::iter::once(0)
+                .chain(offsets.iter().copied())
+                .zip(offsets.iter().copied().chain(std::iter::once(*net_move)))
+                .any(|(from, to)| to < from);
+        }
+    }
+}
+
+impl AST {
+    pub fn estimate_cost(&self) -> Cost {
+        let mut counter = Counter::default();
+        counter.walk(self);
+
+        let Counter { operations, loops } = counter;
+        if loops == 0 {
+            Cost::Bounded(operations)
+        } else {
+            Cost::Unbounded { operations, loops }
+        }
+    }
+
+    /// Longest run of identical consecutive operations, the first one on ties. Loops break
+    /// runs. `None` for programs without operations.
+    pub fn longest_run(&self) -> Option<(Operation, usize)> {
+        fn walk<'a>(mut ast: &'a AST, longest: &mut Option<(&'a Operation, usize)>) {
+            loop {
+                match ast {
+                    AST::Instructions(operations, next) => {
+                        for run in operations.chunk_by(|a, b| a == b) {
+                            if longest.is_none_or(|(_, length)| run.len() > length) {
+                                *longest = Some((&run[0], run.len()));
+                            }
+                        }
+                        ast = next;
+                    }
+                    AST::Loop(body, next) => {
+                        walk(body, longest);
+                        ast = next;
+                    }
+                    AST::EOF => return,
+                }
+            }
+        }
+
+        let mut longest = None;
+        walk(self, &mut longest);
+        longest.map(|(operation, length)| (operation.clone(), length))
+    }
+
+    /// Blocks of the program in source order, each following bracket starting a new, possibly
+    /// empty, block.
+    pub fn basic_blocks(&self) -> Vec<BasicBlock> {
+        let mut blocks = Vec::new();
+        split_blocks(self, 0, &mut blocks);
+        blocks
+    }
+
+    /// Every loop of the program, in the order of their `[`.
+    pub fn loops(&self) -> Vec<LoopInfo> {
+        let mut collector = LoopCollector::default();
+        collector.walk(self);
+        collector.loops
+    }
+
+    pub fn stats(&self) -> ParseStats {
+        let mut collector = StatsCollector::default();
+        collector.walk(self);
+        collector.stats
+    }
+
+    /// Cells as set by the straight-line code at the start of the program, up to the first loop
+    /// or input.
+    pub fn initial_constants(&self) -> Vec<u8> {
+        let mut machine = MachineState::sandboxed();
+
+        if let AST::Instructions(operations, _) = self {
+            for operation in operations.iter().take_while(|op| **op != Operation::Read) {
+                machine
+                    .apply(operation)
+                    .expect("sandboxed machines never fail");
+            }
+        }
+
+        machine.tape().to_vec()
+    }
+
+    /// Whether the program leaves the pointer where it started, or `None` when that depends on
+    /// the tape, as with loops moving the pointer. Like [`symbolic_effect`](AST::symbolic_effect)
+    /// it assumes the program never moves left of the first cell.
+    pub fn is_pointer_balanced(&self) -> Option<bool> {
+        net_movement(self).map(|offset| offset == 0)
+    }
+
+    /// Whether the program never moves left, so that cells behind the pointer are never used
+    /// again. Any left move counts, even in loops that may never run.
+    pub fn is_forward_only(&self) -> bool {
+        let mut left_moves = LeftMoves::default();
+        left_moves.walk(self);
+        !left_moves.0
+    }
+
+    /// Number of cells the program uses when started on the first cell, or `None` when that
+    /// depends on the tape, as with loops moving the pointer or scans.
+    pub fn min_tape_estimate(&self) -> Option<usize> {
+        reach(self, 0).map(|(_, furthest)| furthest + 1)
+    }
+
+    /// Edit distance between the [`flatten`](AST::flatten)ed operations of both programs: the
+    /// number of operations or brackets to insert, remove or replace to go from one to the other.
+    pub fn op_distance(&self, other: &AST) -> usize {
+        let (source, target) = (self.flatten(), other.flatten());
+        let mut previous: Vec<usize> = (0..=target.len()).collect();
+        let mut current = vec![0; target.len() + 1];
+
+        for (i, from) in source.iter().enumerate() {
+            current[0] = i + 1;
+            for (j, to) in target.iter().enumerate() {
+                let replace = previous[j] + usize::from(from != to);
+                current[j + 1] = replace.min(previous[j + 1] + 1).min(current[j] + 1);
+            }
+            std::mem::swap(&mut previous, &mut current);
+        }
+
+        previous[target.len()]
+    }
+
+    /// Net tape effect of the program, or `None` if it has loops, reads input or contains
+    /// fused operations other than runs and blocks.
+    pub fn symbolic_effect(&self) -> Option<Effect> {
+        let mut pointer: isize = 0;
+        let mut deltas: HashMap<isize, u8> = HashMap::new();
+
+        let mut ast = self;
+        loop {
+            match ast {
+                AST::Instructions(operations, next) => {
+                    for operation in operations {
+                        if let Operation::Block {
+                            deltas: changes,
+                            net_move,
+                        } = operation
+                        {
+                            for (offset, delta) in changes {
+                                let delta = delta.rem_euclid(256) as u8;
+                                let cell = deltas.entry(pointer + offset).or_insert(0);
+                                *cell = cell.wrapping_add(delta);
+                            }
+                            pointer += net_move;
+                            continue;
+                        }
+
+                        let (direction, moves, computation, amount) = match operation {
+                            Operation::Move(direction) => (Some(direction), 1, None, 0),
+                            Operation::MoveBy(direction, count) => {
+                                (Some(direction), *count, None, 0)
+                            }
+                            Operation::Change(computation) => (None, 0, Some(computation), 1),
+                            Operation::ChangeBy(computation, amount) => {
+                                (None, 0, Some(computation), *amount)
+                            }
+                            Operation::Print | Operation::Debug => (None, 0, None, 0),
+                            Operation::Read
+                            | Operation::SetZero
+                            | Operation::SetCell(_)
+                            | Operation::Scan(_) => return None,
+                            Operation::Block { .. } => unreachable!(),
+                        };
+
+                        match direction {
+                            Some(Direction::Right) => pointer += moves as isize,
+                            Some(Direction::Left) => pointer -= moves as isize,
+                            Some(Direction::Up | Direction::Down) => return None,
+                            None => {}
+                        }
+
+                        let delta = deltas.entry(pointer).or_insert(0);
+                        match computation {
+                            Some(Computation::Add) => *delta = delta.wrapping_add(amount),
+                            Some(Computation::Substract) => *delta = delta.wrapping_sub(amount),
+                            None => {}
+                        }
+                    }
+                    ast = next;
+                }
+                AST::Loop(..) => return None,
+                AST::EOF => break,
+            }
+        }
+
+        let mut deltas: Vec<(isize, u8)> = deltas.into_iter().filter(|(_, d)| *d != 0).collect();
+        deltas.sort();
+        Some(Effect {
+            pointer_offset: pointer,
+            deltas,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cost_of_programs() {
+        let straight = AST::from_string(String::from("+++>.<"));
+        let looping = AST::from_string(String::from("+[->+[-]<]."));
+
+        assert_eq!(straight.estimate_cost(), Cost::Bounded(6));
+        assert_eq!(
+            looping.estimate_cost(),
+            Cost::Unbounded {
+                operations: 7,
+                loops: 2
+            }
+        );
+        assert_eq!(AST::EOF.estimate_cost(), Cost::Bounded(0));
+        assert!(straight.estimate_cost() < looping.estimate_cost());
+        assert!(AST::from_string("+".repeat(10_000)).estimate_cost() > straight.estimate_cost());
+    }
+
+    #[test]
+    fn longest_runs() {
+        let run = |source: &str| AST::from_string(String::from(source)).longest_run();
+
+        assert_eq!(
+            run("++>>>[----]+++"),
+            Some((Operation::Change(Computation::Substract), 4))
+        );
+        assert_eq!(run("+[+]+"), Some((Operation::Change(Computation::Add), 1)));
+        assert_eq!(run(">>..<<"), Some((Operation::Move(Direction::Right), 2)));
+        assert_eq!(run("[]"), None);
+    }
+
+    #[test]
+    fn blocks_of_programs() {
+        let blocks = AST::from_string(String::from("+[->+<].")).basic_blocks();
+        let ops = |source: &str| AST::from_string(String::from(source)).flatten();
+
+        assert_eq!(blocks.len(), 3);
+        assert_eq!(
+            blocks
+                .iter()
+                .map(|block| (block.depth, block.successors.clone()))
+                .collect::<Vec<_>>(),
+            vec![(0, vec![1, 2]), (1, vec![1, 2]), (0, vec![])]
+        );
+        assert_eq!(
+            AST::Instructions(blocks[1].operations.clone(), Box::from(AST::EOF)).flatten(),
+            ops("->+<")
+        );
+
+        let nested = AST::from_string(String::from("[[-]]")).basic_blocks();
+        assert_eq!(
+            nested
+                .iter()
+                .map(|block| (block.operations.len(), block.successors.clone()))
+                .collect::<Vec<_>>(),
+            vec![
+                (0, vec![1, 4]),
+                (0, vec![2, 3]),
+                (1, vec![2, 3]),
+                (0, vec![1, 4]),
+                (0, vec![])
+            ]
+        );
+    }
+
+    #[test]
+    fn program_stats() {
+        let stats = AST::from_string(String::from("++[>[-]<-]>[.,]")).stats();
+
+        assert_eq!(stats.operations, 9);
+        assert_eq!(stats.loops, 3);
+        assert_eq!(stats.max_depth, 2);
+        assert_eq!(
+            stats.per_operation,
+            [('+', 2), ('>', 2), ('<', 1), ('-', 2), ('.', 1), (',', 1)]
+                .iter()
+                .cloned()
+                .collect()
+        );
+        assert_eq!(AST::EOF.stats(), ParseStats::default());
+    }
+
+    #[test]
+    fn straight_line_effect() {
+        assert_eq!(
+            AST::from_string(String::from(">++>+<<->>>+-.<")).symbolic_effect(),
+            Some(Effect {
+                pointer_offset: 2,
+                deltas: vec![(0, 255), (1, 2), (2, 1)],
+            })
+        );
+        assert_eq!(
+            AST::from_string(String::from("+++>-<"))
+                .optimized()
+                .symbolic_effect(),
+            AST::from_string(String::from("+++>-<")).symbolic_effect()
+        );
+        assert_eq!(AST::EOF.symbolic_effect(), Some(Effect::default()));
+        assert_eq!(
+            AST::from_string(String::from("+[-]")).symbolic_effect(),
+            None
+        );
+        assert_eq!(AST::from_string(String::from("+,")).symbolic_effect(), None);
+    }
+
+    #[test]
+    fn loop_listing() {
+        let loops: Vec<(String, usize)> = AST::from_string(String::from("+[->[-]<]>[.]"))
+            .loops()
+            .into_iter()
+            .map(|info| (info.body, info.depth))
+            .collect();
+
+        assert_eq!(
+            loops,
+            vec![
+                (String::from("->[-]<"), 0),
+                (String::from("-"), 1),
+                (String::from("."), 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn tape_estimates() {
+        let estimate = |source: &str| AST::from_string(String::from(source)).min_tape_estimate();
+
+        assert_eq!(estimate(""), Some(1));
+        assert_eq!(estimate("+[->>+<<]>."), Some(3));
+        assert_eq!(estimate("<<<>>"), Some(3));
+        assert_eq!(estimate("+[>+]"), None);
+        assert_eq!(estimate("+[<>]"), None);
+        assert_eq!(estimate(">+[<>]"), Some(2));
+        assert_eq!(
+            AST::from_string(String::from("+[>]"))
+                .optimized()
+                .min_tape_estimate(),
+            None
+        );
+    }
+
+    #[test]
+    fn forward_only() {
+        let forward = |source: &str| AST::from_string(String::from(source)).is_forward_only();
+
+        assert!(forward("+[.>+]"));
+        assert!(forward(""));
+        assert!(!forward("+[>+<-]"));
+        assert!(!forward("[[-]<]"));
+        assert!(!AST::from_string(String::from("+[<]"))
+            .optimized()
+            .is_forward_only());
+    }
+
+    #[test]
+    fn op_distances() {
+        let distance = |a: &str, b: &str| {
+            AST::from_string(String::from(a)).op_distance(&AST::from_string(String::from(b)))
+        };
+
+        assert_eq!(distance("+[-]>", "+[-]>"), 0);
+        assert_eq!(distance("", "+[-]"), 4);
+        assert_eq!(distance("+[-]", ""), 4);
+        assert_eq!(distance("++>.", "+->."), 1);
+        assert_eq!(distance("+.", "+[.]"), 2);
+
+        let program = AST::from_string(String::from("[-]>>>"));
+        assert_eq!(program.clone().optimized().op_distance(&program), 6);
+    }
+
+    #[test]
+    fn balanced_pointer() {
+        let balanced = |source: &str| AST::from_string(String::from(source)).is_pointer_balanced();
+
+        assert_eq!(balanced("+[>+<-],>.<"), Some(true));
+        assert_eq!(balanced("+[>+<-]>"), Some(false));
+        assert_eq!(balanced("+[>]<"), None);
+        assert_eq!(balanced("+[-]"), Some(true));
+        assert_eq!(
+            AST::from_string(String::from("[-]>>><<<"))
+                .optimized()
+                .is_pointer_balanced(),
+            Some(true)
+        );
+        assert_eq!(
+            AST::from_string(String::from("+[>]"))
+                .optimized()
+                .is_pointer_balanced(),
+            None
+        );
+    }
+
+    #[test]
+    fn constants_before_loop() {
+        let program = AST::from_string(String::from("++++>+++++++<<-.>>>+[>+++]"));
+
+        assert_eq!(program.initial_constants(), vec![3, 7, 0, 1]);
+        assert_eq!(
+            AST::from_string(String::from("+>++,>+++")).initial_constants(),
+            vec![1, 2]
+        );
+        assert_eq!(
+            AST::from_string(String::from("[+]+")).initial_constants(),
+            vec![0]
+        );
+    }
+}
@@ -1,8 +1,157 @@
 use super::machine::Operation;
+use super::visitor::Visitor;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
 use std::fmt;
 use std::str::Chars;
 
+/// Nesting depth accepted by [`AST::from_bytes`].
+pub const DEFAULT_MAX_DEPTH: usize = 1024;
+
+/// End of program marker used by [`AST::parse_prefix`].
+pub const DEFAULT_SENTINEL: char = '!';
+
+/// Element of a program linearized by [`AST::flatten`].
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum FlatOp {
+    Operation(Operation),
+    LoopStart,
+    LoopEnd,
+}
+
+/// Offset in the canonical source of a program, as printed by its `Display` implementation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Position {
+    pub offset: usize,
+}
+
+/// Byte offsets of the brackets of a loop in the parsed source.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Span {
+    pub open: usize,
+    pub close: usize,
+}
+
+/// Source line of each operation of a program, see [`AST::from_with_lines`].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SourceMap {
+    lines: Vec<usize>,
+}
+
+impl SourceMap {
+    /// Line, starting at 1, of the operation at `index` in source order.
+    pub fn line(&self, index: usize) -> Option<usize> {
+        self.lines.get(index).copied()
+    }
+}
+
 #[derive(Debug, PartialEq)]
+pub enum ParseError {
+    UnmatchedOpen(usize),
+    UnmatchedClose(usize),
+    TooDeep(usize),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnmatchedOpen(position) => write!(f, "unmatched '[' at byte {}", position),
+            Self::UnmatchedClose(position) => write!(f, "unmatched ']' at byte {}", position),
+            Self::TooDeep(position) => write!(f, "loop nested too deeply at byte {}", position),
+        }
+    }
+}
+
+impl Error for ParseError {}
+
+struct Parser<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    max_depth: usize,
+    spans: Option<Vec<Span>>,
+    ignored: Option<Vec<usize>>,
+}
+
+impl<'a> Parser<'a> {
+    fn new(bytes: &'a [u8], max_depth: usize) -> Parser<'a> {
+        Parser {
+            bytes,
+            position: 0,
+            max_depth,
+            spans: None,
+            ignored: None,
+        }
+    }
+
+    /// Parses a sequence up to its closing bracket, or to the end of the input for the
+    /// top-level sequence. Only nested loops recurse, sibling loops are chained afterwards.
+    fn parse(&mut self, open: Option<usize>, depth: usize) -> Result<AST, ParseError> {
+        let mut segments: Vec<(Vec<Operation>, AST)> = Vec::new();
+        let mut operations_vec: Vec<Operation> = Vec::new();
+        let mut closed = false;
+
+        while let Some(&byte) = self.bytes.get(self.position) {
+            let position = self.position;
+            self.position += 1;
+
+            if let Some(operation) = Operation::from(byte as char) {
+                operations_vec.push(operation);
+            } else {
+                match byte {
+                    b'[' => {
+                        if depth == self.max_depth {
+                            return Err(ParseError::TooDeep(position));
+                        }
+                        let span = self.spans.as_mut().map(|spans| {
+                            spans.push(Span {
+                                open: position,
+                                close: position,
+                            });
+                            spans.len() - 1
+                        });
+                        let body = self.parse(Some(position), depth + 1)?;
+                        if let (Some(spans), Some(span)) = (self.spans.as_mut(), span) {
+                            spans[span].close = self.position - 1;
+                        }
+                        segments.push((std::mem::take(&mut operations_vec), body));
+                    }
+                    b']' => {
+                        if open.is_none() {
+                            return Err(ParseError::UnmatchedClose(position));
+                        }
+                        closed = true;
+                        break;
+                    }
+                    // Continuation bytes belong to the character already recorded.
+                    _ if byte & 0xC0 == 0x80 => {}
+                    _ => {
+                        if let Some(ignored) = self.ignored.as_mut() {
+                            ignored.push(position);
+                        }
+                    }
+                }
+            }
+        }
+
+        if let (Some(position), false) = (open, closed) {
+            return Err(ParseError::UnmatchedOpen(position));
+        }
+
+        let tail = AST::box_if_not_empty(operations_vec, AST::EOF);
+        Ok(segments
+            .into_iter()
+            .rev()
+            .fold(tail, |next, (operations, body)| {
+                AST::box_if_not_empty(operations, AST::Loop(Box::from(body), Box::from(next)))
+            }))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum AST {
     Instructions(Vec<Operation>, Box<AST>),
     Loop(Box<AST>, Box<AST>),
@@ -14,7 +163,7 @@ impl fmt::Display for AST {
         match self {
             Self::Instructions(operations, next) => {
                 for op in operations {
-                    write!(f, "{}", op.value())?;
+                    write!(f, "{}", op)?;
                 }
                 next.fmt(f)?;
             }
@@ -30,9 +179,35 @@ impl fmt::Display for AST {
     }
 }
 
+impl Visitor for Vec<FlatOp> {
+    fn visit_op(&mut self, operation: &Operation) {
+        self.push(FlatOp::Operation(operation.clone()));
+    }
+
+    fn visit_loop_enter(&mut self, _body: &AST) {
+        self.push(FlatOp::LoopStart);
+    }
+
+    fn visit_loop_exit(&mut self, _body: &AST) {
+        self.push(FlatOp::LoopEnd);
+    }
+}
+
+impl From<&str> for AST {
+    fn from(source: &str) -> AST {
+        AST::from(&mut source.chars())
+    }
+}
+
+impl From<String> for AST {
+    fn from(source: String) -> AST {
+        AST::from_string(source)
+    }
+}
+
 impl AST {
     fn box_if_not_empty(ops: Vec<Operation>, ast: AST) -> AST {
-        if ops.len() != 0 {
+        if !ops.is_empty() {
             AST::Instructions(ops, Box::from(ast))
         } else {
             ast
@@ -40,17 +215,30 @@ impl AST {
     }
 
     pub fn from(program: &mut Chars) -> AST {
+        AST::parse_with(program, Operation::from)
+    }
+
+    /// Same as [`from`](AST::from), also reading the `^` and `v` moves of 2D tapes, see
+    /// [`MachineState::with_2d`](super::machine::MachineState::with_2d).
+    pub fn from_2d(source: &str) -> AST {
+        AST::parse_with(&mut source.chars(), Operation::from_2d)
+    }
+
+    fn parse_with(program: &mut Chars, command: fn(char) -> Option<Operation>) -> AST {
         let mut operations_vec: Vec<Operation> = Vec::new();
 
         while let Some(instr) = program.next() {
-            if let Some(operation) = Operation::from(instr) {
+            if let Some(operation) = command(instr) {
                 operations_vec.push(operation);
             } else {
                 match instr {
                     '[' => {
                         return AST::box_if_not_empty(
                             operations_vec,
-                            AST::Loop(Box::from(AST::from(program)), Box::from(AST::from(program))),
+                            AST::Loop(
+                                Box::from(AST::parse_with(program, command)),
+                                Box::from(AST::parse_with(program, command)),
+                            ),
                         )
                     }
                     ']' => break,
@@ -65,6 +253,197 @@ impl AST {
     pub fn from_string(program: String) -> AST {
         AST::from(&mut program.chars())
     }
+
+    /// Same as [`from`](AST::from), also returning the line of every operation.
+    pub fn from_with_lines(source: &str) -> (AST, SourceMap) {
+        let mut chars = source.chars();
+        let program = AST::from(&mut chars);
+        // Parsing stops at an unmatched `]`, the operations after it aren't part of the program.
+        let parsed = &source[..source.len() - chars.as_str().len()];
+        let lines = parsed
+            .lines()
+            .enumerate()
+            .flat_map(|(number, line)| {
+                line.chars()
+                    .filter(|c| Operation::from(*c).is_some())
+                    .map(move |_| number + 1)
+            })
+            .collect();
+        (program, SourceMap { lines })
+    }
+
+    /// Whether every `[` of `source` is closed, so that a REPL can tell when to stop reading
+    /// lines. Sources with an unmatched `]` are complete too, as more input cannot fix them.
+    pub fn is_complete(source: &str) -> bool {
+        let mut depth: usize = 0;
+        for byte in source.bytes() {
+            match byte {
+                b'[' => depth += 1,
+                b']' if depth == 0 => return true,
+                b']' => depth -= 1,
+                _ => {}
+            }
+        }
+        depth == 0
+    }
+
+    /// Removes the first line of `source` when it is a `#!` line, so that executable scripts
+    /// don't start with a `Debug` operation.
+    pub fn strip_shebang(source: &str) -> &str {
+        if !source.starts_with("#!") {
+            return source;
+        }
+        match source.find('\n') {
+            Some(end) => &source[end + 1..],
+            None => "",
+        }
+    }
+
+    /// Parses `source` up to the first [`DEFAULT_SENTINEL`], returning the text following it.
+    pub fn parse_prefix(source: &str) -> (AST, &str) {
+        AST::parse_prefix_until(source, DEFAULT_SENTINEL)
+    }
+
+    /// Parses `source` up to the first `sentinel`, returning the text following it. Without
+    /// sentinel, the whole source is parsed and the remainder is empty.
+    pub fn parse_prefix_until(source: &str, sentinel: char) -> (AST, &str) {
+        let (program, rest) = match source.find(sentinel) {
+            Some(end) => (&source[..end], &source[end + sentinel.len_utf8()..]),
+            None => (source, ""),
+        };
+        (AST::from(&mut program.chars()), rest)
+    }
+
+    /// Parses the program before the first `!`, the rest of the source being its input, to be
+    /// fed with [`MachineState::with_input_bytes`](super::machine::MachineState::with_input_bytes).
+    pub fn from_with_bang_input(source: &str) -> (AST, Vec<u8>) {
+        let (program, input) = AST::parse_prefix_until(source, '!');
+        (program, input.as_bytes().to_vec())
+    }
+
+    /// Strict parser: unbalanced brackets are errors, and loops may nest up to
+    /// [`DEFAULT_MAX_DEPTH`] levels.
+    pub fn from_bytes(program: &[u8]) -> Result<AST, ParseError> {
+        AST::from_bytes_limited(program, DEFAULT_MAX_DEPTH)
+    }
+
+    pub fn from_bytes_limited(program: &[u8], max_depth: usize) -> Result<AST, ParseError> {
+        Parser::new(program, max_depth).parse(None, 0)
+    }
+
+    /// Same as [`from_bytes`](AST::from_bytes), also returning the span of every loop, in the
+    /// order their `[` appear in the source.
+    pub fn from_with_spans(program: &[u8]) -> Result<(AST, Vec<Span>), ParseError> {
+        let mut parser = Parser::new(program, DEFAULT_MAX_DEPTH);
+        parser.spans = Some(Vec::new());
+        let ast = parser.parse(None, 0)?;
+        Ok((ast, parser.spans.unwrap_or_default()))
+    }
+
+    /// Same as [`from_bytes`](AST::from_bytes), also returning the characters that are not
+    /// commands, with their byte offsets.
+    pub fn from_with_ignored(source: &str) -> Result<(AST, Vec<(usize, char)>), ParseError> {
+        let mut parser = Parser::new(source.as_bytes(), DEFAULT_MAX_DEPTH);
+        parser.ignored = Some(Vec::new());
+        let ast = parser.parse(None, 0)?;
+
+        let ignored = parser
+            .ignored
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|offset| source[offset..].chars().next().map(|c| (offset, c)))
+            .collect();
+        Ok((ast, ignored))
+    }
+
+    pub fn flatten(&self) -> Vec<FlatOp> {
+        let mut flat = Vec::new();
+        flat.walk(self);
+        flat
+    }
+
+    /// Positions of the `[` of every loop of the program, keyed by loop node.
+    pub(crate) fn loop_positions(&self) -> HashMap<*const AST, Position> {
+        fn walk(mut ast: &AST, mut offset: usize, positions: &mut HashMap<*const AST, Position>) {
+            loop {
+                match ast {
+                    AST::Instructions(operations, next) => {
+                        offset += operations
+                            .iter()
+                            .map(|op| op.to_string().len())
+                            .sum::<usize>();
+                        ast = next;
+                    }
+                    AST::Loop(body, next) => {
+                        positions.insert(ast as *const AST, Position { offset });
+                        walk(body, offset + 1, positions);
+                        offset += body.to_string().len() + 2;
+                        ast = next;
+                    }
+                    AST::EOF => return,
+                }
+            }
+        }
+
+        let mut positions = HashMap::new();
+        walk(self, 0, &mut positions);
+        positions
+    }
+
+    /// Index of every operation of the program in source order, keyed by operation.
+    pub(crate) fn operation_indices(&self) -> HashMap<*const Operation, usize> {
+        fn walk(mut ast: &AST, indices: &mut HashMap<*const Operation, usize>) {
+            loop {
+                match ast {
+                    AST::Instructions(operations, next) => {
+                        for operation in operations {
+                            let index = indices.len();
+                            indices.insert(operation as *const Operation, index);
+                        }
+                        ast = next;
+                    }
+                    AST::Loop(body, next) => {
+                        walk(body, indices);
+                        ast = next;
+                    }
+                    AST::EOF => return,
+                }
+            }
+        }
+
+        let mut indices = HashMap::new();
+        walk(self, &mut indices);
+        indices
+    }
+
+    /// Appends `other` after this program, merging the instructions at the junction.
+    pub fn concat(self, other: AST) -> AST {
+        match self {
+            AST::Instructions(mut operations, next) => match (*next, other) {
+                (AST::EOF, AST::Instructions(others, rest)) => {
+                    operations.extend(others);
+                    AST::Instructions(operations, rest)
+                }
+                (next, other) => AST::Instructions(operations, Box::from(next.concat(other))),
+            },
+            AST::Loop(body, next) => AST::Loop(body, Box::from(next.concat(other))),
+            AST::EOF => other,
+        }
+    }
+
+    pub fn repeat(&self, n: usize) -> AST {
+        (0..n).fold(AST::EOF, |program, _| self.clone().concat(program))
+    }
+
+    #[cfg(feature = "serde")]
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string(self)
+    }
+
+    #[cfg(feature = "serde")]
+    pub fn from_json(json: &str) -> serde_json::Result<AST> {
+        serde_json::from_str(json)
+    }
 }
 
 #[cfg(test)]
@@ -141,6 +520,209 @@ mod tests {
         );
     }
 
+    #[test]
+    fn conversions() {
+        let from_str: AST = "+[-]>.".into();
+        let from_string: AST = String::from("+[-]>.").into();
+
+        assert_eq!(from_str, AST::from_string(String::from("+[-]>.")));
+        assert_eq!(from_string, from_str);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn json_round_trip() {
+        let ast = AST::from_string(String::from("+[->+<]>.#,"));
+
+        let json = ast.to_json().expect("Serialization failed");
+
+        assert_eq!(AST::from_json(&json).expect("Deserialization failed"), ast);
+    }
+
+    #[test]
+    fn flatten_program() {
+        let program = AST::from_string(String::from(".[,[]]#"));
+
+        assert_eq!(
+            program.flatten(),
+            vec![
+                FlatOp::Operation(Operation::Print),
+                FlatOp::LoopStart,
+                FlatOp::Operation(Operation::Read),
+                FlatOp::LoopStart,
+                FlatOp::LoopEnd,
+                FlatOp::LoopEnd,
+                FlatOp::Operation(Operation::Debug),
+            ]
+        );
+        assert_eq!(AST::EOF.flatten(), vec![]);
+    }
+
+    #[test]
+    fn canonical_loop_positions() {
+        let program = AST::from_string(String::from("+ [-[.]] [,]"));
+
+        let mut offsets: Vec<usize> = program
+            .loop_positions()
+            .values()
+            .map(|position| position.offset)
+            .collect();
+        offsets.sort();
+
+        assert_eq!(offsets, vec![1, 3, 7]);
+    }
+
+    #[test]
+    fn prefix_parsing() {
+        let (program, rest) = AST::parse_prefix("Some prose\n+[.-]! More prose [.]");
+        assert_eq!(program, AST::from_string(String::from("+[.-]")));
+        assert_eq!(rest, " More prose [.]");
+
+        let (program, rest) = AST::parse_prefix_until("+.~->", '~');
+        assert_eq!(program, AST::from_string(String::from("+.")));
+        assert_eq!(rest, "->");
+
+        let (program, rest) = AST::parse_prefix("+.");
+        assert_eq!(program, AST::from_string(String::from("+.")));
+        assert_eq!(rest, "");
+    }
+
+    #[test]
+    fn operation_lines() {
+        let (program, map) = AST::from_with_lines("+[\n  -a\n]\n\n.>");
+
+        assert_eq!(program, AST::from_string(String::from("+[-].>")));
+        assert_eq!(
+            (0..5).map(|index| map.line(index)).collect::<Vec<_>>(),
+            vec![Some(1), Some(2), Some(5), Some(5), None]
+        );
+
+        let (program, map) = AST::from_with_lines("+\n>]\n-.");
+        assert_eq!(program, AST::from_string(String::from("+>")));
+        assert_eq!(
+            (0..3).map(|index| map.line(index)).collect::<Vec<_>>(),
+            vec![Some(1), Some(2), None]
+        );
+    }
+
+    #[test]
+    fn complete_sources() {
+        assert!(AST::is_complete("+[->+<]."));
+        assert!(AST::is_complete(""));
+        assert!(!AST::is_complete("+[->[-]\n"));
+        assert!(AST::is_complete("+[->[-]\n<]"));
+        assert!(AST::is_complete("]["));
+    }
+
+    #[test]
+    fn shebang() {
+        assert_eq!(
+            AST::strip_shebang("#!/usr/bin/env rebf\n+[-].\n"),
+            "+[-].\n"
+        );
+        assert_eq!(AST::strip_shebang("#!/usr/bin/env rebf"), "");
+        assert_eq!(AST::strip_shebang("#+.\n#!"), "#+.\n#!");
+        assert_eq!(AST::strip_shebang(" #!\n+"), " #!\n+");
+    }
+
+    #[test]
+    fn bang_input() {
+        let (program, input) = AST::from_with_bang_input(",[.,]!hi!");
+
+        assert_eq!(program, AST::from_string(String::from(",[.,]")));
+        assert_eq!(input, b"hi!");
+    }
+
+    #[test]
+    fn strict_parser_matches_lenient() {
+        let source = "+[->+<]comment>[.[,]#]-";
+
+        assert_eq!(
+            AST::from_bytes(source.as_bytes()),
+            Ok(AST::from_string(String::from(source)))
+        );
+    }
+
+    #[test]
+    fn unmatched_brackets() {
+        assert_eq!(AST::from_bytes(b"+[[-]"), Err(ParseError::UnmatchedOpen(1)));
+        assert_eq!(AST::from_bytes(b"[-]]"), Err(ParseError::UnmatchedClose(3)));
+    }
+
+    #[test]
+    fn loop_spans() {
+        let source = b"+[ a [-]>[<] ]. [,]";
+        let (program, spans) = AST::from_with_spans(source).unwrap();
+
+        assert_eq!(program, AST::from_bytes(source).unwrap());
+        assert_eq!(
+            spans,
+            vec![
+                Span { open: 1, close: 13 },
+                Span { open: 5, close: 7 },
+                Span { open: 9, close: 11 },
+                Span {
+                    open: 16,
+                    close: 18
+                },
+            ]
+        );
+        assert_eq!(
+            AST::from_with_spans(b"[[]"),
+            Err(ParseError::UnmatchedOpen(0))
+        );
+    }
+
+    #[test]
+    fn ignored_characters() {
+        let source = "+(é-)[.] x";
+        let (program, ignored) = AST::from_with_ignored(source).unwrap();
+
+        assert_eq!(program, AST::from_string(String::from(source)));
+        assert_eq!(
+            ignored,
+            vec![(1, '('), (2, 'é'), (5, ')'), (9, ' '), (10, 'x')]
+        );
+    }
+
+    #[test]
+    fn depth_limit() {
+        assert!(AST::from_bytes_limited(b"[[[]]]", 3).is_ok());
+        assert_eq!(
+            AST::from_bytes_limited(b"[[[]]]", 2),
+            Err(ParseError::TooDeep(2))
+        );
+
+        let deep = "[".repeat(100_000) + &"]".repeat(100_000);
+        assert_eq!(
+            AST::from_bytes(deep.as_bytes()),
+            Err(ParseError::TooDeep(DEFAULT_MAX_DEPTH))
+        );
+    }
+
+    #[test]
+    fn concat_merges_instructions() {
+        let left = AST::from_string(String::from("[.]+"));
+        let right = AST::from_string(String::from("-[.]"));
+
+        assert_eq!(
+            left.concat(right),
+            AST::from_string(String::from("[.]+-[.]"))
+        );
+    }
+
+    #[test]
+    fn repeat_program() {
+        let program = AST::from_string(String::from(">+[.]"));
+
+        assert_eq!(program.repeat(0), AST::EOF);
+        assert_eq!(program.repeat(1), program);
+        assert_eq!(
+            program.repeat(3),
+            AST::from_string(String::from(">+[.]>+[.]>+[.]"))
+        );
+    }
+
     #[test]
     fn normal_loop() {
         let bf_loop = AST::from_string(String::from("[.]."));
@@ -0,0 +1,573 @@
+use super::ast::{FlatOp, AST};
+use super::machine::{Computation, Direction, Operation};
+use super::program::{Instruction, Program};
+use std::error::Error;
+use std::fmt;
+
+/// Failure to generate code for a program.
+#[derive(Debug, PartialEq)]
+pub enum CodegenError {
+    /// The program moves between the rows of a 2D tape, which generated code has no grid for.
+    RowMove,
+}
+
+impl fmt::Display for CodegenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::RowMove => write!(f, "generated code can't move between rows of a 2D tape"),
+        }
+    }
+}
+
+impl Error for CodegenError {}
+
+fn moves_rows(operation: &Operation) -> bool {
+    matches!(
+        operation,
+        Operation::Move(Direction::Up | Direction::Down)
+            | Operation::MoveBy(Direction::Up | Direction::Down, _)
+            | Operation::Scan(Direction::Up | Direction::Down)
+    )
+}
+
+/// Shortest way to change a cell by `amount`, either directly or with a multiplication loop
+/// counting down on the next cell, which is left at zero.
+fn change(amount: i16) -> String {
+    let step = if amount < 0 { "-" } else { "+" };
+    let amount = amount.unsigned_abs() as usize;
+    let mut best = step.repeat(amount);
+
+    for factor in 2..=amount {
+        let (times, rest) = (amount / factor, amount % factor);
+        if factor + times + rest + 7 < best.len() {
+            best = format!(
+                ">{}[<{}>-]<{}",
+                "+".repeat(factor),
+                step.repeat(times),
+                step.repeat(rest)
+            );
+        }
+    }
+
+    best
+}
+
+const RUST_PRELUDE: &str = "#![allow(unused)]
+use std::io::{self, Read, Write};
+
+fn dump(tape: &[u8], pointer: usize) {
+    let mut dump = String::new();
+    for (index, value) in tape.iter().enumerate() {
+        let marker = if index == pointer { \"<\" } else { \" \" };
+        dump += &format!(\" {:02X} {} \", value, marker);
+        if index % 15 == 0 && index != 0 {
+            dump.push('\\n');
+        }
+    }
+    eprintln!(\"{}\", dump);
+}
+
+fn main() {
+    let mut tape: Vec<u8> = vec![0];
+    let mut pointer: usize = 0;
+    let mut input = io::stdin();
+    let mut output = io::BufWriter::new(io::stdout());
+";
+
+fn rust_move(direction: &Direction, count: usize) -> String {
+    match direction {
+        Direction::Left => format!("pointer = pointer.saturating_sub({});", count),
+        Direction::Right => format!(
+            "pointer += {}; if tape.len() <= pointer {{ tape.resize(pointer + 1, 0); }}",
+            count
+        ),
+        Direction::Up | Direction::Down => unreachable!("row moves are rejected by to_rust"),
+    }
+}
+
+fn rust_change(computation: &Computation, amount: u8) -> String {
+    let method = match computation {
+        Computation::Add => "wrapping_add",
+        Computation::Substract => "wrapping_sub",
+    };
+    format!("tape[pointer] = tape[pointer].{}({});", method, amount)
+}
+
+/// Moves `offset` cells, to the left when negative.
+fn rust_offset(offset: isize) -> Option<String> {
+    match offset {
+        0 => None,
+        offset if offset < 0 => Some(rust_move(&Direction::Left, offset.unsigned_abs())),
+        offset => Some(rust_move(&Direction::Right, offset as usize)),
+    }
+}
+
+fn rust_block_statements(deltas: &[(isize, i16)], net_move: isize) -> String {
+    let mut statements = Vec::new();
+    let mut offset = 0;
+    for (target, delta) in deltas {
+        statements.extend(rust_offset(target - offset));
+        offset = *target;
+        if *delta < 0 {
+            statements.push(rust_change(
+                &Computation::Substract,
+                delta.unsigned_abs() as u8,
+            ));
+        } else if *delta > 0 {
+            statements.push(rust_change(&Computation::Add, *delta as u8));
+        }
+    }
+    statements.extend(rust_offset(net_move - offset));
+    statements.join(" ")
+}
+
+fn rust_statement(operation: &Operation) -> String {
+    match operation {
+        Operation::Move(direction) => rust_move(direction, 1),
+        Operation::MoveBy(direction, count) => rust_move(direction, *count),
+        Operation::Change(computation) => rust_change(computation, 1),
+        Operation::ChangeBy(computation, amount) => rust_change(computation, *amount),
+        Operation::SetZero => String::from("tape[pointer] = 0;"),
+        Operation::SetCell(value) => format!("tape[pointer] = {};", value),
+        Operation::Scan(direction) => {
+            format!("while tape[pointer] != 0 {{ {} }}", rust_move(direction, 1))
+        }
+        Operation::Print => String::from("output.write_all(&[tape[pointer]]).unwrap();"),
+        Operation::Read => String::from(
+            "output.flush().unwrap(); let mut byte = [0]; \
+             tape[pointer] = if input.read(&mut byte).unwrap() == 0 { 0 } else { byte[0] };",
+        ),
+        Operation::Debug => String::from("output.flush().unwrap(); dump(&tape, pointer);"),
+        Operation::Block { deltas, net_move } => rust_block_statements(deltas, *net_move),
+    }
+}
+
+fn rust_block(mut ast: &AST, depth: usize, source: &mut String) {
+    let indent = "    ".repeat(depth);
+    loop {
+        match ast {
+            AST::Instructions(operations, next) => {
+                for operation in operations {
+                    *source += &format!("{}{}\n", indent, rust_statement(operation));
+                }
+                ast = next;
+            }
+            AST::Loop(body, next) => {
+                *source += &format!("{}while tape[pointer] != 0 {{\n", indent);
+                rust_block(body, depth + 1, source);
+                *source += &format!("{}}}\n", indent);
+                ast = next;
+            }
+            AST::EOF => return,
+        }
+    }
+}
+
+const WAT_PRELUDE: &str = "(module
+  (import \"env\" \"read\" (func $read (result i32)))
+  (import \"env\" \"write\" (func $write (param i32)))
+  (import \"env\" \"debug\" (func $debug (param i32)))
+  (memory (export \"memory\") 1)
+  (func $reserve (param $cell i32)
+    (local $pages i32)
+    (local.set $pages
+      (i32.sub (i32.add (i32.shr_u (local.get $cell) (i32.const 16)) (i32.const 1))
+        (memory.size)))
+    (if (i32.gt_s (local.get $pages) (i32.const 0))
+      (then (if (i32.eq (memory.grow (local.get $pages)) (i32.const -1)) (then unreachable)))))
+  (func (export \"run\")
+    (local $pointer i32)
+";
+
+const WAT_CELL: &str = "(i32.load8_u (local.get $pointer))";
+
+fn wat_move(direction: &Direction, count: usize) -> String {
+    match direction {
+        Direction::Left => format!(
+            "(local.set $pointer (select (i32.sub (local.get $pointer) (i32.const {0})) \
+             (i32.const 0) (i32.ge_u (local.get $pointer) (i32.const {0}))))",
+            count
+        ),
+        Direction::Right => format!(
+            "(local.set $pointer (i32.add (local.get $pointer) (i32.const {}))) \
+             (call $reserve (local.get $pointer))",
+            count
+        ),
+        Direction::Up | Direction::Down => unreachable!("row moves are rejected by to_wat"),
+    }
+}
+
+/// Moves `offset` cells, to the left when negative.
+fn wat_offset(offset: isize) -> Option<String> {
+    match offset {
+        0 => None,
+        offset if offset < 0 => Some(wat_move(&Direction::Left, offset.unsigned_abs())),
+        offset => Some(wat_move(&Direction::Right, offset as usize)),
+    }
+}
+
+fn wat_change(computation: &Computation, amount: u8) -> String {
+    let instruction = match computation {
+        Computation::Add => "i32.add",
+        Computation::Substract => "i32.sub",
+    };
+    format!(
+        "(i32.store8 (local.get $pointer) ({} {} (i32.const {})))",
+        instruction, WAT_CELL, amount
+    )
+}
+
+fn wat_statement(operation: &Operation) -> String {
+    match operation {
+        Operation::Move(direction) => wat_move(direction, 1),
+        Operation::MoveBy(direction, count) => wat_move(direction, *count),
+        Operation::Change(computation) => wat_change(computation, 1),
+        Operation::ChangeBy(computation, amount) => wat_change(computation, *amount),
+        Operation::SetZero => String::from("(i32.store8 (local.get $pointer) (i32.const 0))"),
+        Operation::SetCell(value) => {
+            format!("(i32.store8 (local.get $pointer) (i32.const {}))", value)
+        }
+        Operation::Scan(direction) => format!(
+            "(block (loop (br_if 1 (i32.eqz {})) {} (br 0)))",
+            WAT_CELL,
+            wat_move(direction, 1)
+        ),
+        Operation::Print => format!("(call $write {})", WAT_CELL),
+        Operation::Read => String::from("(i32.store8 (local.get $pointer) (call $read))"),
+        Operation::Debug => String::from("(call $debug (local.get $pointer))"),
+        Operation::Block { deltas, net_move } => {
+            let mut statements = Vec::new();
+            let mut offset = 0;
+            for (target, delta) in deltas {
+                statements.extend(wat_offset(target - offset));
+                offset = *target;
+                if *delta < 0 {
+                    statements.push(wat_change(
+                        &Computation::Substract,
+                        delta.unsigned_abs() as u8,
+                    ));
+                } else if *delta > 0 {
+                    statements.push(wat_change(&Computation::Add, *delta as u8));
+                }
+            }
+            statements.extend(wat_offset(net_move - offset));
+            statements.join(" ")
+        }
+    }
+}
+
+fn pretty_block(mut ast: &AST, depth: usize, source: &mut String) {
+    let indent = "    ".repeat(depth);
+    let mut line = String::new();
+    loop {
+        match ast {
+            AST::Instructions(operations, next) => {
+                for operation in operations {
+                    line += &operation.to_string();
+                }
+                ast = next;
+            }
+            AST::Loop(body, next) => {
+                if !line.is_empty() {
+                    *source += &format!("{}{}\n", indent, std::mem::take(&mut line));
+                }
+                *source += &format!("{}[\n", indent);
+                pretty_block(body, depth + 1, source);
+                *source += &format!("{}]\n", indent);
+                ast = next;
+            }
+            AST::EOF => {
+                if !line.is_empty() {
+                    *source += &format!("{}{}\n", indent, line);
+                }
+                return;
+            }
+        }
+    }
+}
+
+impl Program {
+    /// WebAssembly text module running the program when calling its exported `run` function.
+    /// Cells are bytes of the exported `memory`, grown as the pointer moves right. The module
+    /// imports from `env` a `read` function returning the next input byte, or 0 at the end of
+    /// the input, a `write` function printing a byte and a `debug` function given the pointer.
+    /// Fails on programs moving between the rows of a 2D tape.
+    pub fn to_wat(&self) -> Result<String, CodegenError> {
+        let rows = self.instructions().iter().any(|instruction| {
+            matches!(instruction, Instruction::Operation(operation) if moves_rows(operation))
+        });
+        if rows {
+            return Err(CodegenError::RowMove);
+        }
+
+        let mut source = String::from(WAT_PRELUDE);
+        let mut depth = 2;
+        for instruction in self.instructions() {
+            let indent = "  ".repeat(depth);
+            match instruction {
+                Instruction::Operation(operation) => {
+                    source += &format!("{}{}\n", indent, wat_statement(operation));
+                }
+                Instruction::JumpIfZero(_) => {
+                    source += &format!(
+                        "{0}(block\n{0}  (br_if 0 (i32.eqz {1}))\n{0}  (loop\n",
+                        indent, WAT_CELL
+                    );
+                    depth += 2;
+                }
+                Instruction::JumpIfNotZero(_) => {
+                    source += &format!("{}(br_if 0 {})))\n", indent, WAT_CELL);
+                    depth -= 2;
+                }
+            }
+        }
+        source += "  )\n)\n";
+        Ok(source)
+    }
+}
+
+impl AST {
+    /// Source of the program with every bracket on its own line and loop bodies indented by
+    /// four spaces.
+    pub fn to_pretty_string(&self) -> String {
+        let mut source = String::new();
+        pretty_block(self, 0, &mut source);
+        source
+    }
+
+    /// Graphviz graph of the [basic blocks](AST::basic_blocks) of the program, edges going
+    /// back to a loop body being dashed.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph program {\n");
+        let blocks = self.basic_blocks();
+        for (index, block) in blocks.iter().enumerate() {
+            let label: String = block.operations.iter().map(Operation::to_string).collect();
+            dot += &format!("    b{} [label=\"{}\"];\n", index, label);
+        }
+        for (index, block) in blocks.iter().enumerate() {
+            for &successor in &block.successors {
+                let style = if successor <= index {
+                    " [style=dashed]"
+                } else {
+                    ""
+                };
+                dot += &format!("    b{} -> b{}{};\n", index, successor, style);
+            }
+        }
+        dot += "}\n";
+        dot
+    }
+
+    /// Standalone Rust program behaving like this one, to be built with `rustc`. Fails on
+    /// programs moving between the rows of a 2D tape.
+    pub fn to_rust(&self) -> Result<String, CodegenError> {
+        let rows = self.flatten().iter().any(
+            |operation| matches!(operation, FlatOp::Operation(operation) if moves_rows(operation)),
+        );
+        if rows {
+            return Err(CodegenError::RowMove);
+        }
+
+        let mut source = String::from(RUST_PRELUDE);
+        rust_block(self, 1, &mut source);
+        source += "    output.flush().unwrap();\n}\n";
+        Ok(source)
+    }
+
+    /// Program printing exactly `data`, building each byte from the previous one in the first
+    /// cell.
+    pub fn print_bytes(data: &[u8]) -> AST {
+        let mut source = String::new();
+        let mut current: u8 = 0;
+
+        for &byte in data {
+            source += &change(byte.wrapping_sub(current) as i8 as i16);
+            source.push('.');
+            current = byte;
+        }
+
+        AST::from_string(source)
+    }
+
+    /// Program printing `text`, encoded as UTF-8.
+    pub fn print_str(text: &str) -> AST {
+        AST::print_bytes(text.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::machine::{MachineState, OutputMode};
+    use super::super::optimizer::{FoldBlocks, Optimizer};
+    use super::super::output::OutputBuffer;
+    use super::super::program::OptLevel;
+    use super::*;
+
+    fn output(program: &AST) -> Vec<u8> {
+        let output = OutputBuffer::new();
+        MachineState::sandboxed()
+            .with_output(output.clone())
+            .with_output_mode(OutputMode::Raw)
+            .run(program)
+            .expect("Execution failed");
+        output.contents()
+    }
+
+    #[test]
+    fn round_trip() {
+        let samples: [&[u8]; 4] = [
+            b"",
+            b"Hello, World!\n",
+            &[0, 255, 128, 127, 0],
+            "çà €".as_bytes(),
+        ];
+        for data in samples.iter() {
+            assert_eq!(output(&AST::print_bytes(data)), *data);
+        }
+
+        let all: Vec<u8> = (0..=255).rev().chain(0..=255).collect();
+        assert_eq!(output(&AST::print_bytes(&all)), all);
+    }
+
+    #[test]
+    fn pretty_source() {
+        let ast = AST::from_string(String::from("++[>+[-]<-]."));
+
+        assert_eq!(
+            ast.to_pretty_string(),
+            "++\n[\n    >+\n    [\n        -\n    ]\n    <-\n]\n.\n"
+        );
+        assert_eq!(AST::from_string(String::new()).to_pretty_string(), "");
+    }
+
+    #[test]
+    fn dot_graph() {
+        let ast = AST::from_string(String::from("+[->+<]."));
+
+        assert_eq!(
+            ast.to_dot(),
+            "digraph program {\n    \
+             b0 [label=\"+\"];\n    \
+             b1 [label=\"->+<\"];\n    \
+             b2 [label=\".\"];\n    \
+             b0 -> b1;\n    \
+             b0 -> b2;\n    \
+             b1 -> b1 [style=dashed];\n    \
+             b1 -> b2;\n\
+             }\n"
+        );
+    }
+
+    #[test]
+    fn rust_source() {
+        let source = AST::from_string(String::from(">+[<,.>-]"))
+            .optimized()
+            .to_rust()
+            .unwrap();
+
+        assert!(source.starts_with(RUST_PRELUDE));
+        assert!(source.ends_with(
+            "    pointer += 1; if tape.len() <= pointer { tape.resize(pointer + 1, 0); }
+    tape[pointer] = tape[pointer].wrapping_add(1);
+    while tape[pointer] != 0 {
+        pointer = pointer.saturating_sub(1);
+        output.flush().unwrap(); let mut byte = [0]; \
+tape[pointer] = if input.read(&mut byte).unwrap() == 0 { 0 } else { byte[0] };
+        output.write_all(&[tape[pointer]]).unwrap();
+        pointer += 1; if tape.len() <= pointer { tape.resize(pointer + 1, 0); }
+        tape[pointer] = tape[pointer].wrapping_sub(1);
+    }
+    output.flush().unwrap();
+}
+"
+        ));
+    }
+
+    /// Output of the module given by [`Program::to_wat`] on `input`.
+    fn wasm_output(program: &Program, input: &[u8]) -> Vec<u8> {
+        use wasmi::{Caller, Engine, Linker, Module, Store};
+
+        let engine = Engine::default();
+        let module = Module::new(&engine, program.to_wat().unwrap()).expect("invalid module");
+        let mut store = Store::new(&engine, (input.to_vec(), 0, Vec::new()));
+        let mut linker = <Linker<(Vec<u8>, usize, Vec<u8>)>>::new(&engine);
+        linker
+            .func_wrap("env", "read", |mut caller: Caller<'_, _>| {
+                let (input, read, _): &mut (Vec<u8>, usize, Vec<u8>) = caller.data_mut();
+                *read += 1;
+                input.get(*read - 1).copied().map_or(0, i32::from)
+            })
+            .unwrap()
+            .func_wrap("env", "write", |mut caller: Caller<'_, _>, byte: i32| {
+                let (_, _, output): &mut (Vec<u8>, usize, Vec<u8>) = caller.data_mut();
+                output.push(byte as u8);
+            })
+            .unwrap()
+            .func_wrap("env", "debug", |_: Caller<'_, _>, _: i32| {})
+            .unwrap();
+        let instance = linker.instantiate_and_start(&mut store, &module).unwrap();
+        instance
+            .get_typed_func::<(), ()>(&store, "run")
+            .unwrap()
+            .call(&mut store, ())
+            .expect("run failed");
+        store.into_data().2
+    }
+
+    #[test]
+    fn wat_module() {
+        let hello = std::fs::read_to_string("tests/hello_world.bf").unwrap();
+        let sources = [
+            hello,
+            String::from(",[.,]"),
+            String::from(">,>,>,[.<]<<+[>]<<<#."),
+            String::from("<<+.>++[->+++<]>[<+>-]<.[-]>+[<]."),
+            ">".repeat(70_000) + "+++.<<<+[<]-.",
+        ];
+
+        for source in sources.iter() {
+            let program = AST::from_string(source.clone());
+            let expected: Vec<u8> = MachineState::sandboxed()
+                .with_input_bytes(b"bf!")
+                .run_yielding(&program)
+                .collect::<std::io::Result<_>>()
+                .unwrap();
+
+            let folded = Optimizer::new()
+                .with_pass(FoldBlocks)
+                .optimize(program.clone());
+            for compiled in [
+                program.clone().compile_with(OptLevel::None),
+                program.clone().compile_with(OptLevel::Full),
+                folded.compile_with(OptLevel::None),
+            ] {
+                assert_eq!(wasm_output(&compiled, b"bf!"), expected, "{:.20}", source);
+            }
+        }
+    }
+
+    #[test]
+    fn row_moves() {
+        let ast = AST::from_2d("+v+[^]");
+
+        assert_eq!(ast.to_rust(), Err(CodegenError::RowMove));
+        assert_eq!(
+            ast.clone().compile_with(OptLevel::Full).to_wat(),
+            Err(CodegenError::RowMove)
+        );
+        assert!(AST::from_2d("+>+").to_rust().is_ok());
+    }
+
+    #[test]
+    fn multiplication_loops() {
+        assert_eq!(AST::print_bytes(b"\x03").to_string(), "+++.");
+        assert_eq!(
+            AST::print_bytes(b"A").to_string(),
+            ">++++++++[<++++++++>-]<+."
+        );
+        assert_eq!(
+            AST::print_bytes(b"AA").to_string(),
+            ">++++++++[<++++++++>-]<+.."
+        );
+    }
+}
@@ -0,0 +1,146 @@
+use super::machine::Operation;
+use super::stepper::Stepper;
+use std::io::{self, prelude::*};
+
+const HELP: &str = "commands: x START COUNT, set CELL VALUE, s, c";
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Examine(usize, usize),
+    Set(usize, u8),
+    Step,
+    Continue,
+}
+
+impl Command {
+    pub fn parse(line: &str) -> Option<Command> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["x", start, count] => Some(Command::Examine(start.parse().ok()?, count.parse().ok()?)),
+            ["set", cell, value] => Some(Command::Set(cell.parse().ok()?, value.parse().ok()?)),
+            ["s"] => Some(Command::Step),
+            ["c"] => Some(Command::Continue),
+            _ => None,
+        }
+    }
+}
+
+/// Interactive debugger pausing on every `#`, which acts as a breakpoint instead of dumping
+/// the tape.
+pub struct Debugger<'a, R, W> {
+    stepper: Stepper<'a>,
+    commands: R,
+    console: W,
+}
+
+impl<'a, R: BufRead, W: Write> Debugger<'a, R, W> {
+    pub fn new(stepper: Stepper<'a>, commands: R, console: W) -> Debugger<'a, R, W> {
+        Debugger {
+            stepper,
+            commands,
+            console,
+        }
+    }
+
+    pub fn run(&mut self) -> io::Result<()> {
+        let mut stepping = false;
+
+        while let Some(operation) = self.stepper.peek() {
+            if *operation == Operation::Debug {
+                writeln!(self.console, "breakpoint")?;
+                stepping = self.prompt()?;
+                self.stepper.skip();
+            } else if stepping {
+                stepping = self.prompt()?;
+                self.stepper.step()?;
+            } else {
+                self.stepper.step()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads commands until execution resumes, returns whether to stop after the next step.
+    fn prompt(&mut self) -> io::Result<bool> {
+        loop {
+            let next = self.stepper_next();
+            let machine = self.stepper.machine();
+            writeln!(
+                self.console,
+                "pointer {} = {:02X}, next {}",
+                machine.pointer(),
+                machine.tape()[machine.pointer()],
+                next
+            )?;
+            write!(self.console, "(rebf) ")?;
+            self.console.flush()?;
+
+            let mut line = String::new();
+            if self.commands.read_line(&mut line)? == 0 {
+                return Ok(false);
+            }
+
+            match Command::parse(&line) {
+                Some(Command::Examine(start, count)) => {
+                    let tape = self.stepper.machine().tape();
+                    for (index, value) in tape.iter().enumerate().skip(start).take(count) {
+                        writeln!(self.console, "{:5}: {:02X}", index, value)?;
+                    }
+                }
+                Some(Command::Set(cell, value)) => {
+                    if cell < self.stepper.machine().tape().len() {
+                        self.stepper.machine_mut()[cell] = value;
+                    } else {
+                        writeln!(self.console, "no cell {}", cell)?;
+                    }
+                }
+                Some(Command::Step) => return Ok(true),
+                Some(Command::Continue) => return Ok(false),
+                None => writeln!(self.console, "{}", HELP)?,
+            }
+        }
+    }
+
+    fn stepper_next(&mut self) -> String {
+        match self.stepper.peek() {
+            Some(operation) => operation.to_string(),
+            None => String::from("end"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::ast::AST;
+    use super::super::machine::MachineState;
+    use super::*;
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(Command::parse("x 0 20\n"), Some(Command::Examine(0, 20)));
+        assert_eq!(Command::parse("set 5 65"), Some(Command::Set(5, 65)));
+        assert_eq!(Command::parse(" s "), Some(Command::Step));
+        assert_eq!(Command::parse("c"), Some(Command::Continue));
+        assert_eq!(Command::parse("set 5 256"), None);
+        assert_eq!(Command::parse("x 1"), None);
+    }
+
+    #[test]
+    fn scripted_session() {
+        let mut machine = MachineState::sandboxed();
+        let program = AST::from_string(String::from("++>+#+<#-"));
+        let commands: &[u8] = b"x 0 5\nset 0 7\ns\ns\nc\nc\n";
+        let mut console = Vec::new();
+
+        Debugger::new(machine.stepper(&program), commands, &mut console)
+            .run()
+            .expect("Debugging failed");
+
+        let console = String::from_utf8(console).unwrap();
+        assert!(console.contains("    0: 02\n    1: 01\n"));
+        assert!(console.contains("pointer 1 = 02, next <"));
+        assert_eq!(console.matches("breakpoint").count(), 2);
+        assert_eq!(machine.tape(), [6, 2]);
+    }
+}
@@ -0,0 +1,136 @@
+use super::ast::{FlatOp, AST};
+use super::machine::{Operation, COMMANDS};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+fn standard_command(c: char) -> Option<FlatOp> {
+    match c {
+        '[' => Some(FlatOp::LoopStart),
+        ']' => Some(FlatOp::LoopEnd),
+        _ => Operation::from(c).map(FlatOp::Operation),
+    }
+}
+
+/// Mapping from source characters to commands, for Brainfuck variants using other characters.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Dialect {
+    commands: HashMap<char, FlatOp>,
+}
+
+impl Default for Dialect {
+    fn default() -> Dialect {
+        Dialect::standard()
+    }
+}
+
+impl Dialect {
+    pub fn standard() -> Dialect {
+        Dialect {
+            commands: COMMANDS
+                .iter()
+                .copied()
+                .filter_map(|c| standard_command(c).map(|command| (c, command)))
+                .collect(),
+        }
+    }
+
+    /// Makes `c` stand for `command`.
+    pub fn with(mut self, c: char, command: FlatOp) -> Dialect {
+        self.commands.insert(c, command);
+        self
+    }
+
+    /// Standard dialect changed by `config`, where each line holds a character and the standard
+    /// command it stands for, separated by spaces. Empty lines and lines starting with `;` are
+    /// ignored.
+    pub fn from_config(config: &str) -> io::Result<Dialect> {
+        let mut dialect = Dialect::standard();
+
+        for (number, line) in config.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(';') {
+                continue;
+            }
+
+            let mut fields = line.split_whitespace().map(|field| {
+                let mut chars = field.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => Some(c),
+                    _ => None,
+                }
+            });
+            let mapping = match (fields.next(), fields.next(), fields.next()) {
+                (Some(Some(c)), Some(Some(standard)), None) => {
+                    standard_command(standard).map(|command| (c, command))
+                }
+                _ => None,
+            };
+
+            match mapping {
+                Some((c, command)) => dialect = dialect.with(c, command),
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("invalid dialect mapping on line {}", number + 1),
+                    ))
+                }
+            }
+        }
+
+        Ok(dialect)
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Dialect> {
+        Dialect::from_config(&fs::read_to_string(path)?)
+    }
+
+    /// `source` rewritten with the standard command characters, other characters dropped.
+    pub fn translate(&self, source: &str) -> String {
+        source
+            .chars()
+            .filter_map(|c| match self.commands.get(&c)? {
+                FlatOp::Operation(operation) => Some(operation.value()),
+                FlatOp::LoopStart => Some('['),
+                FlatOp::LoopEnd => Some(']'),
+            })
+            .collect()
+    }
+}
+
+impl AST {
+    /// Same as [`from`](AST::from), reading commands as mapped by `dialect`.
+    pub fn from_dialect(source: &str, dialect: &Dialect) -> AST {
+        AST::from(&mut dialect.translate(source).chars())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn swapped_commands() {
+        let dialect = Dialect::from_config("; swap + and -\n+ -\n- +\n\no [\nc ]\n").unwrap();
+
+        assert_eq!(
+            AST::from_dialect("--o+>-<c[.]", &dialect),
+            AST::from_string(String::from("++[->+<][.]"))
+        );
+        assert_eq!(
+            AST::from_dialect("+[-]", &Dialect::default()),
+            AST::from_string(String::from("+[-]"))
+        );
+    }
+
+    #[test]
+    fn invalid_config() {
+        let error = Dialect::from_config("+ -\n+ x\n").unwrap_err();
+
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(error.to_string(), "invalid dialect mapping on line 2");
+        assert!(Dialect::from_config("ab +").is_err());
+        assert!(Dialect::from_config("a + b").is_err());
+    }
+}
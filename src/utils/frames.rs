@@ -0,0 +1,143 @@
+use std::sync::{Arc, Mutex};
+
+/// State of the tape after an operation, one image of an animation of the run.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Frame {
+    pub pointer: usize,
+    pub tape: Vec<u8>,
+}
+
+/// Frames recorded by the machines it is attached to, see
+/// [`with_frame_recorder`](super::machine::MachineState::with_frame_recorder). Clones share
+/// their frames, so that they can be read back after the run.
+#[derive(Clone, Debug, Default)]
+pub struct FrameRecorder {
+    frames: Arc<Mutex<Vec<Frame>>>,
+}
+
+impl FrameRecorder {
+    pub fn new() -> FrameRecorder {
+        FrameRecorder::default()
+    }
+
+    pub fn frames(&self) -> Vec<Frame> {
+        self.frames.lock().unwrap().clone()
+    }
+
+    pub(crate) fn record(&self, pointer: usize, tape: &[u8]) {
+        self.frames.lock().unwrap().push(Frame {
+            pointer,
+            tape: tape.to_vec(),
+        });
+    }
+}
+
+/// Copy of the tape of a running machine, readable from other threads, see
+/// [`with_shared_tape`](super::machine::MachineState::with_shared_tape). Clones share the same
+/// copy.
+#[derive(Clone, Debug, Default)]
+pub struct SharedTape {
+    current: Arc<Mutex<Frame>>,
+}
+
+impl SharedTape {
+    pub fn new() -> SharedTape {
+        SharedTape::default()
+    }
+
+    /// Pointer and tape of the machine after its last operation.
+    pub fn current(&self) -> Frame {
+        self.current.lock().unwrap().clone()
+    }
+
+    /// Copies the cells that may have changed since the last sync: the `written` ones, or the
+    /// whole tape when its length changed.
+    pub(crate) fn sync(&self, pointer: usize, tape: &[u8], written: &[usize]) {
+        let mut current = self.current.lock().unwrap();
+        if current.tape.len() != tape.len() {
+            current.tape = tape.to_vec();
+        } else {
+            for &cell in written {
+                current.tape[cell] = tape[cell];
+            }
+        }
+        current.pointer = pointer;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::ast::AST;
+    use super::super::machine::MachineState;
+    use super::super::optimizer::{FoldBlocks, Optimizer};
+    use super::*;
+
+    #[test]
+    fn record_frames() {
+        let recorder = FrameRecorder::new();
+        let mut machine = MachineState::sandboxed().with_frame_recorder(recorder.clone());
+
+        machine
+            .run(&AST::from_string(String::from("+>++<[-]")))
+            .expect("Execution failed");
+
+        let frames: Vec<(usize, Vec<u8>)> = recorder
+            .frames()
+            .into_iter()
+            .map(|frame| (frame.pointer, frame.tape))
+            .collect();
+        assert_eq!(
+            frames,
+            vec![
+                (0, vec![1]),
+                (1, vec![1, 0]),
+                (1, vec![1, 1]),
+                (1, vec![1, 2]),
+                (0, vec![1, 2]),
+                (0, vec![0, 2]),
+            ]
+        );
+    }
+
+    #[test]
+    fn shared_tape() {
+        let shared = SharedTape::new();
+        let mut machine = MachineState::sandboxed().with_shared_tape(shared.clone());
+        machine
+            .run(&AST::from_string(String::from("++>+++[-<+>]")))
+            .expect("Execution failed");
+
+        let viewer = {
+            let shared = shared.clone();
+            std::thread::spawn(move || shared.current())
+        };
+        assert_eq!(
+            viewer.join().unwrap(),
+            Frame {
+                pointer: 1,
+                tape: vec![5, 0]
+            }
+        );
+        machine[0] = 7;
+        assert_eq!(shared.current().tape, [5, 0]);
+    }
+
+    #[test]
+    fn shared_tape_blocks() {
+        let program = Optimizer::new()
+            .with_pass(FoldBlocks)
+            .optimize(AST::from_string(String::from(">>><<<+++[->++>+<<]>->-<")));
+        let shared = SharedTape::new();
+        let mut machine = MachineState::sandboxed().with_shared_tape(shared.clone());
+        machine.run(&program).expect("Execution failed");
+
+        assert_eq!(
+            shared.current(),
+            Frame {
+                pointer: 1,
+                tape: machine.tape().to_vec(),
+            }
+        );
+        assert_eq!(shared.current().tape, [0, 5, 2, 0]);
+    }
+}
@@ -0,0 +1,59 @@
+use std::io::{self, prelude::*};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::Duration;
+
+/// Reads from another reader on a background thread, so that a read can give up when no byte
+/// arrives in time. Giving up is reported as the end of the input.
+pub(crate) struct ThreadedInput {
+    bytes: Receiver<io::Result<u8>>,
+    timeout: Duration,
+}
+
+impl ThreadedInput {
+    /// With a zero `timeout`, reads only return bytes the thread already received.
+    pub(crate) fn new(mut input: Box<dyn Read + Send>, timeout: Duration) -> ThreadedInput {
+        let (sender, bytes) = mpsc::sync_channel(0);
+
+        thread::spawn(move || {
+            let mut byte = [0];
+            loop {
+                let received = match input.read(&mut byte) {
+                    Ok(0) => break,
+                    Ok(_) => Ok(byte[0]),
+                    Err(ref error) if error.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(error) => Err(error),
+                };
+                let failed = received.is_err();
+                if sender.send(received).is_err() || failed {
+                    break;
+                }
+            }
+        });
+
+        ThreadedInput { bytes, timeout }
+    }
+}
+
+impl Read for ThreadedInput {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let received = if self.timeout == Duration::ZERO {
+            self.bytes.try_recv().ok()
+        } else {
+            self.bytes.recv_timeout(self.timeout).ok()
+        };
+
+        match received {
+            Some(Ok(byte)) => {
+                buf[0] = byte;
+                Ok(1)
+            }
+            Some(Err(error)) => Err(error),
+            None => Ok(0),
+        }
+    }
+}
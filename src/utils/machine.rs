@@ -1,39 +1,92 @@
 use super::ast::*;
+use super::frames::{FrameRecorder, SharedTape};
+use super::input::ThreadedInput;
+use super::output::OutputBuffer;
+use super::snapshot::Snapshot;
+use super::stepper::{OutputBytes, Stepper};
+#[cfg(feature = "mmap")]
+use super::tape::MmapTape;
+use super::tape::{DefaultTape, FixedTape, Tape};
+use super::transcript::{IoEvent, Transcript};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
 use std::fmt;
+use std::fs;
 use std::io::{self, prelude::*};
-use std::ops::{Index, IndexMut};
+use std::net::TcpStream;
+use std::ops::{Index, IndexMut, Range};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Direction {
     Left,
     Right,
+    /// Previous row of a 2D tape, only parsed by [`AST::from_2d`].
+    Up,
+    /// Next row of a 2D tape, only parsed by [`AST::from_2d`].
+    Down,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Computation {
     Add,
     Substract,
 }
 
-#[derive(Debug, PartialEq)]
+/// Characters of the standard commands, the loop brackets included. Any other character is a
+/// comment.
+pub const COMMANDS: &[char] = &['>', '<', '+', '-', '.', ',', '#', '[', ']'];
+
+/// Whether `c` is one of the [`COMMANDS`].
+pub fn is_command(c: char) -> bool {
+    COMMANDS.contains(&c)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Operation {
     Move(Direction),
     Change(Computation),
     Print,
     Read,
     Debug,
+    // Fused operations, only produced by the optimizer.
+    MoveBy(Direction, usize),
+    ChangeBy(Computation, u8),
+    SetZero,
+    /// Sets the current cell to the given value, as `[-]` followed by a run of changes.
+    SetCell(u8),
+    Scan(Direction),
+    /// Adds each delta to the cell at its offset from the pointer, visiting the offsets in
+    /// order, then leaves the pointer `net_move` cells from where it started.
+    Block {
+        deltas: Vec<(isize, i16)>,
+        net_move: isize,
+    },
 }
 
 impl Operation {
+    /// Command character of the operation. Fused operations report the first character of
+    /// their source form.
     pub fn value(&self) -> char {
         match self {
-            Self::Move(Direction::Right) => '>',
-            Self::Move(Direction::Left) => '<',
-            Self::Change(Computation::Add) => '+',
-            Self::Change(Computation::Substract) => '-',
+            Self::Move(Direction::Right) | Self::MoveBy(Direction::Right, _) => '>',
+            Self::Move(Direction::Left) | Self::MoveBy(Direction::Left, _) => '<',
+            Self::Move(Direction::Up) | Self::MoveBy(Direction::Up, _) => '^',
+            Self::Move(Direction::Down) | Self::MoveBy(Direction::Down, _) => 'v',
+            Self::Change(Computation::Add) | Self::ChangeBy(Computation::Add, _) => '+',
+            Self::Change(Computation::Substract) | Self::ChangeBy(Computation::Substract, _) => '-',
             Self::Print => '.',
             Self::Read => ',',
             Self::Debug => '#',
+            Self::SetZero | Self::SetCell(_) | Self::Scan(_) => '[',
+            Self::Block { deltas, net_move } => block_value(deltas, *net_move),
         }
     }
 
@@ -49,17 +102,327 @@ impl Operation {
             _ => None,
         }
     }
+
+    /// Same as [`from`](Operation::from), also reading `^` and `v` as moves between the rows
+    /// of a 2D tape.
+    pub fn from_2d(instr: char) -> Option<Operation> {
+        match instr {
+            '^' => Some(Self::Move(Direction::Up)),
+            'v' => Some(Self::Move(Direction::Down)),
+            _ => Operation::from(instr),
+        }
+    }
 }
 
 impl fmt::Display for Operation {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.value())
+        match self {
+            Self::MoveBy(_, count) => write!(f, "{}", self.value().to_string().repeat(*count)),
+            Self::ChangeBy(_, count) => {
+                write!(f, "{}", self.value().to_string().repeat(*count as usize))
+            }
+            Self::SetZero => write!(f, "[-]"),
+            Self::SetCell(value) => write!(f, "[-]{}", "+".repeat(*value as usize)),
+            Self::Scan(direction) => {
+                write!(f, "[{}]", Operation::Move(direction.clone()).value())
+            }
+            Self::Block { deltas, net_move } => {
+                let mut offset = 0;
+                for (target, delta) in deltas {
+                    write_moves(f, target - offset)?;
+                    offset = *target;
+                    let change = if *delta < 0 { "-" } else { "+" };
+                    write!(f, "{}", change.repeat(delta.unsigned_abs() as usize))?;
+                }
+                write_moves(f, net_move - offset)
+            }
+            _ => write!(f, "{}", self.value()),
+        }
+    }
+}
+
+/// First character of the source form of a block, `>` for empty blocks.
+fn block_value(deltas: &[(isize, i16)], net_move: isize) -> char {
+    for (target, delta) in deltas {
+        if *target != 0 {
+            return if *target < 0 { '<' } else { '>' };
+        }
+        if *delta != 0 {
+            return if *delta < 0 { '-' } else { '+' };
+        }
+    }
+    if net_move < 0 {
+        '<'
+    } else {
+        '>'
+    }
+}
+
+fn write_moves(f: &mut fmt::Formatter<'_>, offset: isize) -> fmt::Result {
+    let direction = if offset < 0 { "<" } else { ">" };
+    write!(f, "{}", direction.repeat(offset.unsigned_abs()))
+}
+
+/// Failure of a run, reported as the inner error of an [`io::Error`] of kind
+/// [`Other`](io::ErrorKind::Other).
+#[derive(Debug, PartialEq)]
+pub enum RunError {
+    /// The pointer ended on this cell instead of the first one.
+    UnbalancedPointer(usize),
+    /// A `Print` went past the output limit, of this many bytes.
+    OutputLimitReached(usize),
+    /// The loop whose `[` is at this position iterated more times than allowed in a row.
+    LoopLimitExceeded(Position),
+    /// The pointer went past the tape limit, of this many cells.
+    TapeLimitReached(usize),
+    /// The run was cancelled from another thread.
+    Cancelled,
+}
+
+impl fmt::Display for RunError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnbalancedPointer(pointer) => {
+                write!(f, "pointer ended on cell {} instead of 0", pointer)
+            }
+            Self::OutputLimitReached(limit) => {
+                write!(f, "output limit of {} bytes reached", limit)
+            }
+            Self::LoopLimitExceeded(position) => write!(
+                f,
+                "loop at offset {} exceeded its iteration limit",
+                position.offset
+            ),
+            Self::TapeLimitReached(limit) => write!(f, "tape limit of {} cells reached", limit),
+            Self::Cancelled => write!(f, "run cancelled"),
+        }
+    }
+}
+
+impl Error for RunError {}
+
+/// Error of a run, with the source line of the operation that failed.
+#[derive(Debug)]
+pub struct LineError {
+    pub line: usize,
+    pub error: io::Error,
+}
+
+impl fmt::Display for LineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "error on line {}: {}", self.line, self.error)
+    }
+}
+
+impl Error for LineError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// Run error behind `error`, looking through the [`LineError`] of located errors.
+fn run_error(error: &io::Error) -> Option<&RunError> {
+    let inner = error.get_ref()?;
+    match inner.downcast_ref::<LineError>() {
+        Some(located) => run_error(&located.error),
+        None => inner.downcast_ref::<RunError>(),
+    }
+}
+
+/// Resources used by a run, see [`run_measured`](MachineState::run_measured).
+#[derive(Clone, Debug, PartialEq)]
+pub struct Measurement {
+    /// Cells from the start of the tape up to the furthest one the pointer reached during the
+    /// run.
+    pub peak_tape: usize,
+    /// Operations executed by the run.
+    pub executed: u64,
+    /// Whether the run was stopped by the tape limit.
+    pub limit_reached: bool,
+}
+
+impl From<RunError> for io::Error {
+    fn from(error: RunError) -> io::Error {
+        io::Error::other(error)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputMode {
+    /// Each cell is written as the byte it holds.
+    Raw,
+    Hex,
+    /// Each cell is the Unicode scalar value to print, written as UTF-8. This is the default,
+    /// so that cells above `0x7F` print as the characters U+0080 to U+00FF.
+    Unicode,
+}
+
+/// Operations and loop iterations between two checks of the flag of
+/// [`run_cancellable`](MachineState::run_cancellable).
+const CANCEL_INTERVAL: u32 = 4096;
+
+fn check_cancel(cancel: &AtomicBool, countdown: &mut u32) -> io::Result<()> {
+    if *countdown == 0 {
+        *countdown = CANCEL_INTERVAL;
+        if cancel.load(Ordering::Relaxed) {
+            return Err(RunError::Cancelled.into());
+        }
+    }
+    *countdown -= 1;
+    Ok(())
+}
+
+#[cfg(windows)]
+const NEWLINE: &[u8] = b"\r\n";
+#[cfg(not(windows))]
+const NEWLINE: &[u8] = b"\n";
+
+#[derive(Default)]
+struct LoopProfile {
+    positions: HashMap<*const AST, Position>,
+    iterations: HashMap<Position, u64>,
+}
+
+/// Position of the pointer from which a streaming tape drops the cells behind it, see
+/// [`with_streaming_tape`](MachineState::with_streaming_tape).
+const STREAMING_WINDOW: usize = 4096;
+
+/// Cells dropped by a streaming tape.
+struct StreamingTape {
+    active: bool,
+    discarded: usize,
+}
+
+/// Wall-clock time spent in each category of operations, see
+/// [`with_timing`](MachineState::with_timing). Fused operations count in the category of the
+/// operations they replace, `Debug` in none.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Timing {
+    pub moves: Duration,
+    pub changes: Duration,
+    pub prints: Duration,
+    pub reads: Duration,
+    /// Whole runs, including loop tests and other overhead.
+    pub total: Duration,
+    /// Operations executed during the timed runs.
+    pub operations: u64,
+}
+
+impl Timing {
+    fn category(&mut self, instr: &Operation) -> Option<&mut Duration> {
+        match instr {
+            Operation::Move(_) | Operation::MoveBy(..) | Operation::Scan(_) => {
+                Some(&mut self.moves)
+            }
+            Operation::Change(_)
+            | Operation::ChangeBy(..)
+            | Operation::SetZero
+            | Operation::SetCell(_)
+            | Operation::Block { .. } => Some(&mut self.changes),
+            Operation::Print => Some(&mut self.prints),
+            Operation::Read => Some(&mut self.reads),
+            Operation::Debug => None,
+        }
+    }
+}
+
+struct LoopLimit {
+    iterations: u64,
+    positions: HashMap<*const AST, Position>,
+}
+
+/// SplitMix64 generator, small and good enough to fill cells.
+#[derive(Clone)]
+struct Rng(u64);
+
+impl Rng {
+    fn next_byte(&mut self) -> u8 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        (z ^ (z >> 31)) as u8
+    }
+}
+
+impl Read for Rng {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        buf.iter_mut().for_each(|byte| *byte = self.next_byte());
+        Ok(buf.len())
     }
 }
 
+#[cfg(feature = "serde")]
+#[derive(Serialize)]
+struct TraceRecord {
+    op: String,
+    ptr: usize,
+    cell: u8,
+}
+
+struct SourceLines {
+    map: SourceMap,
+    indices: HashMap<*const Operation, usize>,
+}
+
+struct Checkpoint {
+    interval: u64,
+    callback: Box<dyn FnMut(&Snapshot)>,
+    locations: HashMap<*const Operation, usize>,
+}
+
 pub struct MachineState {
     pointer: usize,
-    memory: Vec<u8>,
+    memory: Box<dyn Tape>,
+    input: Box<dyn Read + Send>,
+    output: Box<dyn Write + Send>,
+    debug_output: Box<dyn Write + Send>,
+    output_mode: OutputMode,
+    printed: usize,
+    output_limit: Option<usize>,
+    transactional_output: bool,
+    tape_limit: Option<usize>,
+    translate_newlines: bool,
+    move_callback: Option<Box<dyn FnMut(usize, usize)>>,
+    output_callbacks: HashMap<u8, Box<dyn FnMut()>>,
+    loop_profile: Option<LoopProfile>,
+    loop_limit: Option<LoopLimit>,
+    executed: u64,
+    checkpoint: Option<Checkpoint>,
+    written: Option<Vec<bool>>,
+    uninit_warnings: bool,
+    warnings: Box<dyn Write + Send>,
+    unread: Vec<u8>,
+    last_read: Option<u8>,
+    saturating: bool,
+    ring: bool,
+    ring_clamped: bool,
+    ring_display: bool,
+    grow_chunk: usize,
+    grid_width: Option<usize>,
+    labels: Vec<(Range<usize>, String)>,
+    transcript: Option<Transcript>,
+    debug_snapshots: Option<Vec<Snapshot>>,
+    trimmed_debug: bool,
+    debug_window: Option<usize>,
+    /// Cells written by the last `Block`, with their previous values and written flags, in the
+    /// order written.
+    block_writes: Vec<(usize, u8, bool)>,
+    source_lines: Option<SourceLines>,
+    random_init: Option<Rng>,
+    timing: Option<Timing>,
+    /// Wall-clock time and operations of whole runs, see
+    /// [`with_throughput`](MachineState::with_throughput).
+    throughput: Option<(Duration, u64)>,
+    streaming_tape: Option<StreamingTape>,
+    cancel_countdown: u32,
+    /// Furthest cell reached by the pointer during [`run_measured`](MachineState::run_measured),
+    /// counting the cells dropped by a streaming tape.
+    peak_pointer: Option<usize>,
+    frame_recorder: Option<FrameRecorder>,
+    shared_tape: Option<SharedTape>,
+    #[cfg(feature = "serde")]
+    json_trace: Option<Box<dyn Write + Send>>,
 }
 
 impl Index<usize> for MachineState {
@@ -78,207 +441,2628 @@ impl IndexMut<usize> for MachineState {
 
 impl fmt::Display for MachineState {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        for (index, value) in self.memory.iter().enumerate() {
-            write!(
-                f,
-                " {:02X} {} ",
-                value,
-                if index == self.pointer { "<" } else { " " }
-            )?;
-            if index % 15 == 0 && index != 0 {
-                write!(f, "\n")?;
-            }
+        self.write_cells(f, 0..self.memory.len())?;
+        if self.ring && self.ring_display {
+            write!(f, "-> {:02X}", self.memory[0])?;
         }
-
         Ok(())
     }
 }
 
+impl Default for MachineState {
+    fn default() -> MachineState {
+        MachineState::new()
+    }
+}
+
 impl MachineState {
     pub fn new() -> MachineState {
         MachineState {
             pointer: 0,
-            memory: vec![0],
+            memory: Box::new(DefaultTape::from(&[0][..])),
+            input: Box::new(io::stdin()),
+            output: Box::new(io::stdout()),
+            debug_output: Box::new(io::stderr()),
+            output_mode: OutputMode::Unicode,
+            printed: 0,
+            output_limit: None,
+            transactional_output: false,
+            tape_limit: None,
+            translate_newlines: false,
+            move_callback: None,
+            output_callbacks: HashMap::new(),
+            loop_profile: None,
+            loop_limit: None,
+            executed: 0,
+            checkpoint: None,
+            written: None,
+            uninit_warnings: false,
+            warnings: Box::new(io::stderr()),
+            unread: Vec::new(),
+            last_read: None,
+            saturating: false,
+            ring: false,
+            ring_clamped: false,
+            ring_display: false,
+            grow_chunk: 1,
+            grid_width: None,
+            labels: Vec::new(),
+            transcript: None,
+            debug_snapshots: None,
+            trimmed_debug: false,
+            debug_window: None,
+            block_writes: Vec::new(),
+            source_lines: None,
+            random_init: None,
+            timing: None,
+            throughput: None,
+            streaming_tape: None,
+            cancel_countdown: 0,
+            peak_pointer: None,
+            frame_recorder: None,
+            shared_tape: None,
+            #[cfg(feature = "serde")]
+            json_trace: None,
         }
     }
 
-    fn get_current(&self) -> u8 {
-        self[self.pointer]
-    }
-
-    fn pointer_move(&mut self, direction: &Direction) -> () {
-        match direction {
-            Direction::Left => {
-                if self.pointer != 0 {
-                    self.pointer -= 1;
-                }
-            }
-            Direction::Right => {
-                self.pointer += 1;
-                if self.pointer == self.memory.len() {
-                    self.memory.push(0u8);
-                }
-            }
-        };
+    /// Machine in the state captured by `snapshot`, with the default IO.
+    pub fn from_snapshot(snapshot: &Snapshot) -> MachineState {
+        MachineState {
+            pointer: snapshot.pointer,
+            memory: Box::new(snapshot.tape.clone()),
+            executed: snapshot.executed,
+            ..MachineState::new()
+        }
     }
 
-    fn change(&mut self, operation: &Computation) -> () {
-        let pointer = self.pointer;
-        match operation {
-            Computation::Add => {
-                if self.get_current() == 255 {
-                    self[pointer] = 0;
-                } else {
-                    self[pointer] += 1;
-                }
-            }
-            Computation::Substract => {
-                if self.get_current() == 0 {
-                    self[pointer] = 255;
-                } else {
-                    self[pointer] -= 1;
-                }
-            }
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            pointer: self.pointer,
+            tape: self.tape().to_vec(),
+            executed: self.executed,
+            location: None,
         }
     }
 
-    fn print(&self) -> () {
-        print!("{}", self.get_current() as char)
+    /// Reports the line of the failing operation in the errors of `run`, `map` being the source
+    /// map of the programs to run, as given by [`AST::from_with_lines`]. Errors are wrapped in a
+    /// [`LineError`] keeping their kind.
+    pub fn with_source_map(mut self, map: SourceMap) -> MachineState {
+        self.source_lines = Some(SourceLines {
+            map,
+            indices: HashMap::new(),
+        });
+        self
     }
 
-    fn read(&mut self) -> io::Result<()> {
-        let mut input: [u8; 1] = [0];
-        io::stdin().read(&mut input)?;
+    /// Calls `callback` with a snapshot every `interval` operations executed by `run`.
+    pub fn with_checkpoint<F>(mut self, interval: u64, callback: F) -> MachineState
+    where
+        F: FnMut(&Snapshot) + 'static,
+    {
+        assert!(interval > 0, "checkpoint interval must be positive");
+        self.checkpoint = Some(Checkpoint {
+            interval,
+            callback: Box::new(callback),
+            locations: HashMap::new(),
+        });
+        self
+    }
 
-        let pointer = self.pointer;
-        self[pointer] = input[0];
+    /// Operations executed by this machine so far.
+    pub fn executed(&self) -> u64 {
+        self.executed
+    }
 
-        Ok(())
+    /// Runs on a fixed size tape of `size` cells stored in the file at `path`. Moving right
+    /// from the last cell does nothing, like moving left from the first one.
+    #[cfg(feature = "mmap")]
+    pub fn with_mmap_tape<P: AsRef<Path>>(path: P, size: usize) -> io::Result<MachineState> {
+        Ok(MachineState::with_tape(MmapTape::open(path, size)?))
     }
 
-    fn apply(&mut self, instr: &Operation) -> io::Result<&Vec<u8>> {
-        match instr {
-            Operation::Move(dir) => {
-                self.pointer_move(dir);
-            }
-            Operation::Change(op) => {
-                self.change(op);
-            }
-            Operation::Print => {
-                self.print();
-            }
-            Operation::Read => {
-                self.read()?;
-            }
-            Operation::Debug => {
-                println!("{}", self);
-            }
+    /// Runs on `tape`, which must hold at least one cell.
+    pub fn with_tape<T: Tape + 'static>(tape: T) -> MachineState {
+        assert!(!tape.is_empty(), "tape needs at least one cell");
+        MachineState {
+            memory: Box::new(tape),
+            ..MachineState::new()
         }
-        Ok(&self.memory)
     }
 
-    pub fn run(&mut self, instructions: &AST) -> io::Result<&Vec<u8>> {
-        match instructions {
-            AST::Instructions(operations, next) => {
-                for op in operations {
-                    self.apply(op)?;
-                }
-                self.run(next)
-            }
-            AST::Loop(body, next) => {
-                while self.get_current() != 0 {
-                    self.run(body)?;
-                }
-                self.run(next)
-            }
-            AST::EOF => Ok(&self.memory),
+    /// Runs on a ring of `size` cells: moving right from the last cell leads to the first one,
+    /// and moving left from the first cell to the last one, so that `size` moves in the same
+    /// direction come back to the starting cell. A ring of one cell never moves. Panics when
+    /// `size` is 0.
+    pub fn with_ring_tape(size: usize) -> MachineState {
+        MachineState {
+            ring: true,
+            ..MachineState::with_tape(FixedTape(vec![0; size].into()))
         }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::Computation;
-    use super::Direction;
-    use super::*;
+    /// Stops the pointer on the first and last cells of a ring tape instead of wrapping around,
+    /// so that `size` moves right from the first cell end on the last one.
+    pub fn with_ring_clamping(mut self) -> MachineState {
+        self.ring_clamped = true;
+        self
+    }
 
-    #[test]
-    fn operation_valid() {
-        assert_eq!(
-            Operation::from(Operation::Debug.value()),
-            Some(Operation::Debug)
-        );
+    /// Displays a ring tape as a circle, its last cell being followed by an arrow back to the
+    /// value of the first one.
+    pub fn with_ring_display(mut self) -> MachineState {
+        self.ring_display = true;
+        self
+    }
 
-        assert_eq!(
-            Operation::from(Operation::Print.value()),
-            Some(Operation::Print)
-        );
+    /// Grows the tape by at least `cells` cells at a time when moving right of its end, so that
+    /// programs sweeping right reallocate less often. The extra cells are part of the tape.
+    pub fn with_grow_chunk(mut self, cells: usize) -> MachineState {
+        assert!(cells > 0, "grow chunk must be positive");
+        self.grow_chunk = cells;
+        self
+    }
 
-        assert_eq!(
-            Operation::from(Operation::Read.value()),
-            Some(Operation::Read)
-        );
+    /// Runs on a 2D tape, a grid stored row after row. `<` and `>` move within a row, `^` and
+    /// `v` between rows, and the grid grows to the right and downwards as needed. On fixed size
+    /// tapes the grid is a single row and the pointer stops at its edges. Without this mode, `^`
+    /// and `v` do nothing.
+    pub fn with_2d(mut self) -> MachineState {
+        self.grid_width = Some(self.memory.len());
+        self
+    }
 
-        assert_eq!(
-            Operation::from(Operation::Change(Computation::Add).value()),
-            Some(Operation::Change(Computation::Add))
-        );
+    /// Row and column of the pointer on a 2D tape.
+    pub fn grid_position(&self) -> Option<(usize, usize)> {
+        self.grid_width
+            .map(|width| (self.pointer / width, self.pointer % width))
+    }
 
-        assert_eq!(
-            Operation::from(Operation::Change(Computation::Substract).value()),
-            Some(Operation::Change(Computation::Substract))
+    /// Starts the pointer on cell `index`, growing the tape up to it. Panics when the tape is
+    /// too short and cannot grow.
+    pub fn with_start_pointer(mut self, index: usize) -> MachineState {
+        self.memory.grow(index + 1);
+        assert!(
+            index < self.memory.len(),
+            "start pointer {} is past the end of the tape",
+            index
         );
+        self.pointer = index;
+        self
+    }
 
-        assert_eq!(
-            Operation::from(Operation::Move(Direction::Left).value()),
-            Some(Operation::Move(Direction::Left))
-        );
+    /// A machine without side effects: `Read` always yields zero, while `Print` and `Debug`
+    /// output, as well as warnings, is discarded.
+    pub fn sandboxed() -> MachineState {
+        MachineState::new()
+            .with_input(io::repeat(0))
+            .with_output(io::sink())
+            .with_debug_output(io::sink())
+            .with_warning_output(io::sink())
+    }
 
-        assert_eq!(
-            Operation::from(Operation::Move(Direction::Right).value()),
-            Some(Operation::Move(Direction::Right))
-        );
+    /// Reads from `input` through a buffer, so that it is read in large chunks rather than byte
+    /// per byte. The buffer reads ahead of the program: bytes it holds are lost when the input is
+    /// replaced or the machine dropped.
+    pub fn with_input<R>(mut self, input: R) -> MachineState
+    where
+        R: Read + Send + 'static,
+    {
+        self.input = Box::new(io::BufReader::new(input));
+        self
     }
 
-    #[test]
-    fn change_value() {
-        let mut machine = MachineState::new();
+    /// Reads from and prints to `stream`, for programs served over the network. Output is
+    /// flushed before each read, so that the peer sees prompts before answering them.
+    pub fn with_tcp_stream(self, stream: TcpStream) -> io::Result<MachineState> {
+        Ok(self.with_input(stream.try_clone()?).with_output(stream))
+    }
 
-        machine.change(&Computation::Add);
+    pub fn with_input_bytes(self, input: &[u8]) -> MachineState {
+        self.with_input(io::Cursor::new(input.to_vec()))
+    }
 
-        assert_eq!(machine.get_current(), 1);
+    /// Reads an endless stream of pseudo-random bytes derived from `seed`, to explore the
+    /// behaviour of programs on many inputs reproducibly.
+    pub fn with_random_input(mut self, seed: u64) -> MachineState {
+        self.input = Box::new(Rng(seed));
+        self
+    }
 
-        machine.change(&Computation::Substract);
+    pub fn with_output<W>(mut self, output: W) -> MachineState
+    where
+        W: Write + Send + 'static,
+    {
+        self.output = Box::new(output);
+        self
+    }
 
-        assert_eq!(machine.get_current(), 0);
+    /// Sink for the tape dumps of `Debug`, stderr by default so that they don't mix with the
+    /// program output.
+    pub fn with_debug_output<W>(mut self, debug_output: W) -> MachineState
+    where
+        W: Write + Send + 'static,
+    {
+        self.debug_output = Box::new(debug_output);
+        self
     }
 
-    #[test]
-    fn move_ptr() {
-        let mut machine = MachineState::new();
+    /// Makes `Debug` record a snapshot instead of printing the tape, see
+    /// [`debug_snapshots`](MachineState::debug_snapshots).
+    pub fn with_debug_snapshots(mut self) -> MachineState {
+        self.debug_snapshots = Some(Vec::new());
+        self
+    }
 
-        machine.pointer_move(&Direction::Right);
-        machine.change(&Computation::Add);
+    /// Makes `Debug` print only the [`used_range`](MachineState::used_range) of the tape.
+    pub fn with_trimmed_debug(mut self) -> MachineState {
+        self.trimmed_debug = true;
+        self
+    }
 
-        assert_eq!(machine[1], 1);
+    /// Makes `Debug` print only the cells at most `radius` cells away from the pointer, taking
+    /// precedence over [`with_trimmed_debug`](MachineState::with_trimmed_debug).
+    pub fn with_debug_window(mut self, radius: usize) -> MachineState {
+        self.debug_window = Some(radius);
+        self
+    }
 
-        machine.pointer_move(&Direction::Left);
-        machine.change(&Computation::Add);
+    /// Snapshots recorded by each `Debug`, in execution order.
+    pub fn debug_snapshots(&self) -> &[Snapshot] {
+        self.debug_snapshots.as_deref().unwrap_or(&[])
+    }
 
-        assert_eq!(machine[0], 1);
+    /// Makes `Read` behave as if the input ended when no byte is immediately available,
+    /// instead of blocking. The current input is read on a background thread, so bytes may only
+    /// become available shortly after they were written.
+    pub fn with_nonblocking_input(mut self) -> MachineState {
+        let input = std::mem::replace(&mut self.input, Box::new(io::empty()));
+        self.input = Box::new(ThreadedInput::new(input, Duration::ZERO));
+        self
     }
 
-    #[test]
-    fn change_overflow() {
-        let mut machine = MachineState::new();
+    /// Makes each `Read` wait at most `timeout` for a byte, then behave as if the input ended.
+    /// Like [`with_nonblocking_input`](MachineState::with_nonblocking_input), the current input
+    /// is read on a background thread.
+    pub fn with_read_timeout(mut self, timeout: Duration) -> MachineState {
+        let input = std::mem::replace(&mut self.input, Box::new(io::empty()));
+        self.input = Box::new(ThreadedInput::new(input, timeout));
+        self
+    }
 
-        machine.change(&Computation::Substract);
+    /// In [`OutputMode::Hex`], each printed byte is written as two hex digits, separated by
+    /// spaces.
+    pub fn with_output_mode(mut self, mode: OutputMode) -> MachineState {
+        self.output_mode = mode;
+        self
+    }
 
-        assert_eq!(machine.get_current(), 255);
+    /// Makes `run` fail with [`RunError::OutputLimitReached`] on the first `Print` past
+    /// `max_bytes` printed bytes.
+    pub fn with_output_limit(mut self, max_bytes: usize) -> MachineState {
+        self.output_limit = Some(max_bytes);
+        self
+    }
 
-        machine.change(&Computation::Add);
+    /// Buffers the output of `run`, only writing it to the output once the run succeeds. The
+    /// output of failed runs is discarded.
+    pub fn with_transactional_output(mut self) -> MachineState {
+        self.transactional_output = true;
+        self
+    }
 
-        assert_eq!(machine.get_current(), 0);
+    /// Fails with [`RunError::TapeLimitReached`] when the pointer would move past the first
+    /// `cells` cells.
+    pub fn with_tape_limit(mut self, cells: usize) -> MachineState {
+        self.tape_limit = Some(cells);
+        self
+    }
+
+    /// Prints `0x0A` as the platform newline instead of the raw byte.
+    pub fn with_newline_translation(mut self) -> MachineState {
+        self.translate_newlines = true;
+        self
+    }
+
+    /// Counts the iterations of every loop executed by `run`, see
+    /// [`loop_hotspots`](MachineState::loop_hotspots).
+    pub fn with_loop_profiling(mut self) -> MachineState {
+        self.loop_profile = Some(LoopProfile::default());
+        self
+    }
+
+    /// Makes `run` fail with [`RunError::LoopLimitExceeded`] when a loop iterates more than
+    /// `iterations` times without being left. The count restarts each time the loop is entered.
+    pub fn with_loop_iteration_limit(mut self, iterations: u64) -> MachineState {
+        self.loop_limit = Some(LoopLimit {
+            iterations,
+            positions: HashMap::new(),
+        });
+        self
+    }
+
+    /// Total iterations per loop, busiest loops first. Loops are located in the canonical
+    /// source of the program, and loops that never iterated are left out.
+    pub fn loop_hotspots(&self) -> Vec<(Position, u64)> {
+        let mut hotspots: Vec<(Position, u64)> = match &self.loop_profile {
+            Some(profile) => profile.iterations.iter().map(|(p, n)| (*p, *n)).collect(),
+            None => Vec::new(),
+        };
+        hotspots.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then(a.cmp(b)));
+        hotspots
+    }
+
+    /// Drops the cells behind the pointer as it moves right, for programs where
+    /// [`AST::is_forward_only`] holds. Other programs run on the whole tape. Once cells are
+    /// dropped, the tape, the pointer and the positions given to callbacks only cover the
+    /// remaining cells, see [`discarded`](MachineState::discarded). Tapes that can't drop cells,
+    /// such as fixed size tapes and the window of [`run_subroutine`](MachineState::run_subroutine),
+    /// are kept whole.
+    pub fn with_streaming_tape(mut self) -> MachineState {
+        self.streaming_tape = Some(StreamingTape {
+            active: false,
+            discarded: 0,
+        });
+        self
+    }
+
+    /// Cells dropped from the front of the tape by the streaming tape, the pointer being
+    /// `discarded() + pointer()` cells from the start of the original tape.
+    pub fn discarded(&self) -> usize {
+        self.streaming_tape
+            .as_ref()
+            .map_or(0, |streaming| streaming.discarded)
+    }
+
+    /// Measures the time spent in each category of operations, see
+    /// [`timing`](MachineState::timing). Timestamps are only taken when enabled.
+    pub fn with_timing(mut self) -> MachineState {
+        self.timing = Some(Timing::default());
+        self
+    }
+
+    /// Time spent so far in each category of operations, when timing is enabled.
+    pub fn timing(&self) -> Option<&Timing> {
+        self.timing.as_ref()
+    }
+
+    /// Measures the wall-clock time of whole runs and the operations they execute, for
+    /// [`ops_per_second`](MachineState::ops_per_second). Unlike
+    /// [`with_timing`](MachineState::with_timing), operations are not timed one by one, so runs
+    /// keep the fast path.
+    pub fn with_throughput(mut self) -> MachineState {
+        self.throughput = Some((Duration::ZERO, 0));
+        self
+    }
+
+    /// Operations executed per second of wall-clock time by the runs so far, when throughput
+    /// or timing is measured and some time was. Throughput is preferred, as timing slows down
+    /// the runs it measures.
+    pub fn ops_per_second(&self) -> Option<f64> {
+        let (total, operations) = match (&self.throughput, &self.timing) {
+            (Some(throughput), _) => *throughput,
+            (None, Some(timing)) => (timing.total, timing.operations),
+            (None, None) => return None,
+        };
+        let seconds = total.as_secs_f64();
+        if seconds > 0.0 {
+            Some(operations as f64 / seconds)
+        } else {
+            None
+        }
+    }
+
+    /// Writes a JSON line for every operation executed, like `{"op":".","ptr":3,"cell":65}`,
+    /// with the pointer and the current cell after the operation.
+    #[cfg(feature = "serde")]
+    pub fn with_json_trace<W>(mut self, writer: W) -> MachineState
+    where
+        W: Write + Send + 'static,
+    {
+        self.json_trace = Some(Box::new(writer));
+        self
+    }
+
+    /// Fills the cells added when the tape grows with pseudo-random values derived from
+    /// `seed`, instead of zeros, to catch programs relying on zeroed cells.
+    pub fn with_random_init(mut self, seed: u64) -> MachineState {
+        self.random_init = Some(Rng(seed));
+        self
+    }
+
+    /// Makes cells stop at 255 and 0 instead of wrapping around. Optimized programs assume
+    /// wrapping cells, so they may behave differently in this mode.
+    pub fn with_saturating_cells(mut self) -> MachineState {
+        self.saturating = true;
+        self
+    }
+
+    /// Warns when `Print` or `Change` reads a cell that was never written.
+    pub fn with_uninit_warnings(mut self) -> MachineState {
+        self.uninit_warnings = true;
+        self.with_write_tracking()
+    }
+
+    /// Records which cells are written, see [`written_cells`](MachineState::written_cells).
+    pub fn with_write_tracking(mut self) -> MachineState {
+        self.written.get_or_insert_with(Vec::new);
+        self
+    }
+
+    /// Number of distinct cells set by `Change`, `Read` or a cleared loop so far, as opposed to
+    /// the length of the tape. Zero unless writes are tracked.
+    pub fn written_cells(&self) -> usize {
+        self.written
+            .as_ref()
+            .map_or(0, |written| written.iter().filter(|&&cell| cell).count())
+    }
+
+    /// Sink for the warnings, stderr by default.
+    pub fn with_warning_output<W>(mut self, warnings: W) -> MachineState
+    where
+        W: Write + Send + 'static,
+    {
+        self.warnings = Box::new(warnings);
+        self
+    }
+
+    /// Calls `callback(old, new)` on every pointer move. Moving left from the first cell still
+    /// counts as a move, with both pointers equal.
+    pub fn on_move<F>(mut self, callback: F) -> MachineState
+    where
+        F: FnMut(usize, usize) + 'static,
+    {
+        self.move_callback = Some(Box::new(callback));
+        self
+    }
+
+    /// Calls `callback` each time `byte` is printed, right after it is written. Registering
+    /// another callback for the same byte replaces the previous one.
+    pub fn on_output_byte<F>(mut self, byte: u8, callback: F) -> MachineState
+    where
+        F: FnMut() + 'static,
+    {
+        self.output_callbacks.insert(byte, Box::new(callback));
+        self
+    }
+
+    /// Records a frame in `recorder` after every operation executed.
+    pub fn with_frame_recorder(mut self, recorder: FrameRecorder) -> MachineState {
+        self.frame_recorder = Some(recorder);
+        self
+    }
+
+    /// Keeps `tape` up to date with the tape after every operation, so that other threads can
+    /// watch the run. Changes made through indexing show up after the next operation.
+    pub fn with_shared_tape(mut self, tape: SharedTape) -> MachineState {
+        tape.sync(self.pointer, self.memory.as_slice(), &[]);
+        self.shared_tape = Some(tape);
+        self
+    }
+
+    /// Records every printed and consumed byte, see [`transcript`](MachineState::transcript).
+    pub fn with_transcript(mut self) -> MachineState {
+        self.transcript = Some(Transcript::default());
+        self
+    }
+
+    pub fn transcript(&self) -> Option<&Transcript> {
+        self.transcript.as_ref()
+    }
+
+    /// Names the cells in `range` in the tape dumps of `Debug`.
+    pub fn label_region(&mut self, range: Range<usize>, name: &str) {
+        self.labels.push((range, String::from(name)));
+    }
+
+    pub fn pointer(&self) -> usize {
+        self.pointer
+    }
+
+    /// Value of the cell at `index`, `None` past the end of the tape.
+    pub fn get(&self, index: usize) -> Option<u8> {
+        self.tape().get(index).copied()
+    }
+
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut u8> {
+        if index < self.memory.len() {
+            Some(&mut self.memory[index])
+        } else {
+            None
+        }
+    }
+
+    pub fn tape(&self) -> &[u8] {
+        self.memory.as_slice()
+    }
+
+    /// Writes the raw bytes of the tape to the file at `path`.
+    pub fn dump_tape<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, self.tape())
+    }
+
+    pub(crate) fn get_current(&self) -> u8 {
+        self[self.pointer]
+    }
+
+    fn pointer_move(&mut self, direction: &Direction) {
+        self.pointer_move_by(direction, 1);
+    }
+
+    /// Moves on a 2D tape of rows of `width` cells, doubling the width when moving right of
+    /// the last column.
+    /// Row, column and grid width after moving `count` cells towards `direction` on a 2D tape
+    /// `width` cells wide, along with the tape length the move needs.
+    fn grid_target(
+        &self,
+        width: usize,
+        direction: &Direction,
+        count: usize,
+    ) -> (usize, usize, usize, usize) {
+        let (mut row, mut column) = (self.pointer / width, self.pointer % width);
+        match direction {
+            Direction::Left => column = column.saturating_sub(count),
+            Direction::Right => column += count,
+            Direction::Up => row = row.saturating_sub(count),
+            Direction::Down => row += count,
+        }
+
+        let mut new_width = width;
+        while column >= new_width {
+            new_width *= 2;
+        }
+        let rows = self.memory.len().div_ceil(width).max(row + 1);
+        (row, column, new_width, rows * new_width)
+    }
+
+    fn shift_2d(&mut self, width: usize, direction: &Direction, count: usize) {
+        let (row, column, new_width, needed) = self.grid_target(width, direction, count);
+        let len = self.memory.len();
+        self.memory.grow(needed);
+        if self.memory.len() < needed {
+            // Fixed size tapes can't grow: stay on the grid they hold, as the 1D tape does.
+            let rows = len / width;
+            self.pointer = row.min(rows - 1) * width + column.min(width - 1);
+            return;
+        }
+
+        if new_width != width {
+            // Spread the rows in place, last cell first, so that no cell is overwritten before
+            // it moves.
+            for index in (0..len).rev() {
+                self.memory[index / width * new_width + index % width] = self.memory[index];
+            }
+            self.grid_width = Some(new_width);
+        }
+        if needed > len || new_width != width {
+            for index in 0..needed {
+                if index >= len || index % new_width >= width {
+                    self.memory[index] = self.random_init.as_mut().map_or(0, Rng::next_byte);
+                }
+            }
+        }
+
+        self.pointer = row * new_width + column;
+    }
+
+    fn shift(&mut self, direction: &Direction, count: usize) {
+        self.shift_pointer(direction, count);
+        if self.peak_pointer.is_some() {
+            let reached = self.discarded() + self.pointer;
+            self.peak_pointer = self.peak_pointer.map(|peak| peak.max(reached));
+        }
+    }
+
+    fn shift_pointer(&mut self, direction: &Direction, count: usize) {
+        if let Some(width) = self.grid_width {
+            return self.shift_2d(width, direction, count);
+        }
+        if !matches!(direction, Direction::Left | Direction::Right) {
+            return;
+        }
+        if self.ring && !self.ring_clamped {
+            let len = self.memory.len();
+            self.pointer = match direction {
+                Direction::Right => (self.pointer + count % len) % len,
+                _ => (self.pointer + len - count % len) % len,
+            };
+            return;
+        }
+
+        match direction {
+            Direction::Right => {
+                let target = self.pointer + count;
+                let len = self.memory.len();
+                if target >= len {
+                    self.memory.grow((target + 1).max(len + self.grow_chunk));
+                }
+                if let Some(rng) = self.random_init.as_mut() {
+                    for index in len..self.memory.len() {
+                        self.memory[index] = rng.next_byte();
+                    }
+                }
+                self.pointer = target.min(self.memory.len() - 1);
+            }
+            _ => {
+                self.pointer = self.pointer.saturating_sub(count);
+            }
+        };
+
+        if let Some(streaming) = self.streaming_tape.as_mut() {
+            if streaming.active
+                && self.pointer >= STREAMING_WINDOW
+                && self.memory.drop_front(self.pointer)
+            {
+                streaming.discarded += self.pointer;
+                self.pointer = 0;
+            }
+        }
+    }
+
+    fn check_tape_limit(&self, direction: &Direction, count: usize) -> io::Result<()> {
+        let Some(limit) = self.tape_limit else {
+            return Ok(());
+        };
+        let needed = match (self.grid_width, direction) {
+            (None, Direction::Right) => self.discarded() + self.pointer + count + 1,
+            (Some(width), Direction::Right | Direction::Down) => {
+                self.grid_target(width, direction, count).3
+            }
+            _ => return Ok(()),
+        };
+        if needed > limit {
+            Err(RunError::TapeLimitReached(limit).into())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn pointer_move_by(&mut self, direction: &Direction, count: usize) {
+        let old = self.pointer;
+        self.shift(direction, count);
+
+        if let Some(callback) = self.move_callback.as_mut() {
+            callback(old, self.pointer);
+        }
+    }
+
+    fn change(&mut self, operation: &Computation) {
+        if self.saturating {
+            return self.change_by(operation, 1);
+        }
+
+        let pointer = self.pointer;
+        match operation {
+            Computation::Add => {
+                if self.get_current() == 255 {
+                    self[pointer] = 0;
+                } else {
+                    self[pointer] += 1;
+                }
+            }
+            Computation::Substract => {
+                if self.get_current() == 0 {
+                    self[pointer] = 255;
+                } else {
+                    self[pointer] -= 1;
+                }
+            }
+        }
+    }
+
+    fn change_by(&mut self, operation: &Computation, amount: u8) {
+        let pointer = self.pointer;
+        self[pointer] = match (operation, self.saturating) {
+            (Computation::Add, false) => self[pointer].wrapping_add(amount),
+            (Computation::Substract, false) => self[pointer].wrapping_sub(amount),
+            (Computation::Add, true) => self[pointer].saturating_add(amount),
+            (Computation::Substract, true) => self[pointer].saturating_sub(amount),
+        };
+    }
+
+    /// Moves the pointer `offset` cells, to the left when negative.
+    fn move_relative(&mut self, offset: isize) -> io::Result<()> {
+        let direction = if offset < 0 {
+            Direction::Left
+        } else {
+            Direction::Right
+        };
+        let count = offset.unsigned_abs();
+        if count > 0 {
+            self.check_tape_limit(&direction, count)?;
+            self.pointer_move_by(&direction, count);
+        }
+        Ok(())
+    }
+
+    fn apply_block(&mut self, deltas: &[(isize, i16)], net_move: isize) -> io::Result<()> {
+        self.block_writes.clear();
+        let mut offset = 0;
+        for (target, delta) in deltas {
+            self.move_relative(target - offset)?;
+            offset = *target;
+            if *delta != 0 {
+                let computation = if *delta < 0 {
+                    Computation::Substract
+                } else {
+                    Computation::Add
+                };
+                let amount = delta.unsigned_abs() as u8;
+                let pointer = self.pointer;
+                self.block_writes
+                    .push((pointer, self.get_current(), self.is_written(pointer)));
+                self.track_writes(&Operation::ChangeBy(computation.clone(), amount))?;
+                self.change_by(&computation, amount);
+            }
+        }
+        self.move_relative(net_move - offset)
+    }
+
+    fn print(&mut self) -> io::Result<()> {
+        if let Some(limit) = self.output_limit {
+            if self.printed >= limit {
+                return Err(RunError::OutputLimitReached(limit).into());
+            }
+        }
+
+        let value = self.get_current();
+        if let Some(transcript) = self.transcript.as_mut() {
+            transcript.events.push(IoEvent::Output(value));
+        }
+        match self.output_mode {
+            OutputMode::Raw | OutputMode::Unicode if self.translate_newlines && value == b'\n' => {
+                self.output.write_all(NEWLINE)?
+            }
+            OutputMode::Raw => self.output.write_all(&[value])?,
+            OutputMode::Unicode => {
+                let mut encoded = [0; 4];
+                self.output
+                    .write_all(char::from(value).encode_utf8(&mut encoded).as_bytes())?
+            }
+            OutputMode::Hex => {
+                if self.printed != 0 {
+                    self.output.write_all(b" ")?;
+                }
+                write!(self.output, "{:02X}", value)?
+            }
+        }
+        self.printed += 1;
+        if let Some(callback) = self.output_callbacks.get_mut(&value) {
+            callback();
+        }
+        Ok(())
+    }
+
+    /// Cells from the first to the last nonzero one, widened to include the pointer.
+    pub fn used_range(&self) -> Range<usize> {
+        let tape = self.memory.as_slice();
+        let start = tape
+            .iter()
+            .position(|&value| value != 0)
+            .unwrap_or(self.pointer);
+        let end = tape
+            .iter()
+            .rposition(|&value| value != 0)
+            .unwrap_or(self.pointer);
+        start.min(self.pointer)..end.max(self.pointer) + 1
+    }
+
+    fn write_cells<W: fmt::Write>(&self, f: &mut W, cells: Range<usize>) -> fmt::Result {
+        for index in cells {
+            for (_, name) in self.labels.iter().filter(|(range, _)| range.start == index) {
+                write!(f, " {}:", name)?;
+            }
+            write!(
+                f,
+                " {:02X} {} ",
+                self.memory[index],
+                if index == self.pointer { "<" } else { " " }
+            )?;
+            if index % 15 == 0 && index != 0 {
+                writeln!(f)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn debug(&mut self) -> io::Result<()> {
+        if let Some(mut snapshots) = self.debug_snapshots.take() {
+            snapshots.push(self.snapshot());
+            self.debug_snapshots = Some(snapshots);
+            return Ok(());
+        }
+
+        let mut dump = String::new();
+        let cells = match self.debug_window {
+            Some(radius) => {
+                let end = self.pointer.saturating_add(radius).saturating_add(1);
+                self.pointer.saturating_sub(radius)..end.min(self.memory.len())
+            }
+            None if self.trimmed_debug => self.used_range(),
+            None => 0..self.memory.len(),
+        };
+        self.write_cells(&mut dump, cells)
+            .expect("formatting into a string cannot fail");
+        writeln!(self.debug_output, "{}", dump)
+    }
+
+    fn read(&mut self) -> io::Result<()> {
+        let value = match self.unread.pop() {
+            Some(value) => Some(value),
+            None => {
+                // Anything printed may be a prompt the input waits on, as with sockets.
+                self.output.flush()?;
+                let mut input: [u8; 1] = [0];
+                let read = self.input.read(&mut input)?;
+                if read == 0 {
+                    None
+                } else {
+                    Some(input[0])
+                }
+            }
+        };
+        self.last_read = value;
+        if let (Some(transcript), Some(byte)) = (self.transcript.as_mut(), value) {
+            transcript.events.push(IoEvent::Input(byte));
+        }
+
+        let pointer = self.pointer;
+        self[pointer] = value.unwrap_or(0);
+
+        Ok(())
+    }
+
+    /// Byte consumed by the last `Read`, `None` if it hit the end of the input.
+    pub(crate) fn last_read(&self) -> Option<u8> {
+        self.last_read
+    }
+
+    /// Cells written by the last `Block` applied, with their values and written flags from
+    /// before it.
+    pub(crate) fn block_writes(&self) -> &[(usize, u8, bool)] {
+        &self.block_writes
+    }
+
+    /// Whether `cell` is recorded as written, see
+    /// [`with_write_tracking`](MachineState::with_write_tracking).
+    pub(crate) fn is_written(&self, cell: usize) -> bool {
+        self.written
+            .as_ref()
+            .is_some_and(|written| written.get(cell) == Some(&true))
+    }
+
+    /// Reverts an operation, given the pointer from before it ran and the previous values and
+    /// written flags of the cells it wrote. A byte it consumed is read again by the next `Read`.
+    pub(crate) fn undo(
+        &mut self,
+        pointer: usize,
+        cells: &[(usize, u8, bool)],
+        consumed: Option<u8>,
+    ) {
+        self.pointer = pointer;
+        for &(cell, value, was_written) in cells.iter().rev() {
+            self[cell] = value;
+            if let Some(flag) = self
+                .written
+                .as_mut()
+                .and_then(|written| written.get_mut(cell))
+            {
+                *flag = was_written;
+            }
+        }
+        self.executed -= 1;
+        self.unread.extend(consumed);
+    }
+
+    fn track_writes(&mut self, instr: &Operation) -> io::Result<()> {
+        let pointer = self.pointer;
+        let written = match self.written.as_mut() {
+            Some(written) => written,
+            None => return Ok(()),
+        };
+        if written.len() <= pointer {
+            written.resize(pointer + 1, false);
+        }
+
+        let reads = matches!(
+            instr,
+            Operation::Print | Operation::Change(_) | Operation::ChangeBy(..)
+        );
+        if self.uninit_warnings && reads && !written[pointer] {
+            writeln!(
+                self.warnings,
+                "warning: `{}` reads uninitialized cell {}",
+                instr, pointer
+            )?;
+        }
+
+        if matches!(
+            instr,
+            Operation::Change(_)
+                | Operation::ChangeBy(..)
+                | Operation::SetZero
+                | Operation::SetCell(_)
+                | Operation::Read
+        ) {
+            written[pointer] = true;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn apply(&mut self, instr: &Operation) -> io::Result<&[u8]> {
+        self.executed += 1;
+        self.track_writes(instr)?;
+        let start = self.timing.as_ref().map(|_| Instant::now());
+        match instr {
+            Operation::Move(dir) => {
+                self.check_tape_limit(dir, 1)?;
+                self.pointer_move(dir);
+            }
+            Operation::Change(op) => {
+                self.change(op);
+            }
+            Operation::Print => {
+                self.print()?;
+            }
+            Operation::Read => {
+                self.read()?;
+            }
+            Operation::Debug => {
+                self.debug()?;
+            }
+            Operation::MoveBy(dir, count) => {
+                self.check_tape_limit(dir, *count)?;
+                self.pointer_move_by(dir, *count);
+            }
+            Operation::ChangeBy(op, amount) => {
+                self.change_by(op, *amount);
+            }
+            Operation::SetZero => {
+                let pointer = self.pointer;
+                self[pointer] = 0;
+            }
+            Operation::SetCell(value) => {
+                let pointer = self.pointer;
+                self[pointer] = *value;
+            }
+            Operation::Scan(dir) => {
+                while self.get_current() != 0 {
+                    self.check_tape_limit(dir, 1)?;
+                    self.pointer_move(dir);
+                }
+            }
+            Operation::Block { deltas, net_move } => {
+                self.apply_block(deltas, *net_move)?;
+            }
+        }
+        if let (Some(timing), Some(start)) = (self.timing.as_mut(), start) {
+            if let Some(spent) = timing.category(instr) {
+                *spent += start.elapsed();
+            }
+        }
+
+        if let Some(recorder) = &self.frame_recorder {
+            recorder.record(self.pointer, self.memory.as_slice());
+        }
+        if let Some(shared) = &self.shared_tape {
+            match instr {
+                Operation::Block { .. } => {
+                    let written: Vec<usize> =
+                        self.block_writes.iter().map(|(cell, ..)| *cell).collect();
+                    shared.sync(self.pointer, self.memory.as_slice(), &written)
+                }
+                _ => shared.sync(self.pointer, self.memory.as_slice(), &[self.pointer]),
+            }
+        }
+
+        if let Some(mut checkpoint) = self.checkpoint.take() {
+            if self.executed.is_multiple_of(checkpoint.interval) {
+                let snapshot = Snapshot {
+                    location: checkpoint.locations.get(&(instr as *const _)).copied(),
+                    ..self.snapshot()
+                };
+                (checkpoint.callback)(&snapshot);
+            }
+            self.checkpoint = Some(checkpoint);
+        }
+
+        #[cfg(feature = "serde")]
+        if let Some(trace) = self.json_trace.as_mut() {
+            let record = TraceRecord {
+                op: instr.to_string(),
+                ptr: self.pointer,
+                cell: self.memory[self.pointer],
+            };
+            serde_json::to_writer(&mut *trace, &record)?;
+            trace.write_all(b"\n")?;
+        }
+
+        Ok(self.memory.as_slice())
+    }
+
+    /// Whether none of the optional modes watching each operation is enabled.
+    fn is_plain(&self) -> bool {
+        self.move_callback.is_none()
+            && self.loop_profile.is_none()
+            && self.loop_limit.is_none()
+            && self.checkpoint.is_none()
+            && self.written.is_none()
+            && !self.saturating
+            && self.source_lines.is_none()
+            && self.timing.is_none()
+            && self.tape_limit.is_none()
+            && self.frame_recorder.is_none()
+            && self.shared_tape.is_none()
+            && self.is_untraced()
+    }
+
+    #[cfg(feature = "serde")]
+    fn is_untraced(&self) -> bool {
+        self.json_trace.is_none()
+    }
+
+    #[cfg(not(feature = "serde"))]
+    fn is_untraced(&self) -> bool {
+        true
+    }
+
+    /// Runs `program` ignoring the modes that watch each operation: move callbacks, loop
+    /// profiling and limits, checkpoints, uninitialized reads warnings, saturating cells, source
+    /// lines, timing, the tape limit, frame recording, shared tapes and JSON traces. [`run`]
+    /// takes this path by itself when none of them is enabled.
+    ///
+    /// [`run`]: MachineState::run
+    pub fn run_fast(&mut self, program: &AST) -> io::Result<&[u8]> {
+        self.execute_fast(program)?;
+        Ok(self.memory.as_slice())
+    }
+
+    fn execute_fast(&mut self, mut instructions: &AST) -> io::Result<()> {
+        loop {
+            match instructions {
+                AST::Instructions(operations, next) => {
+                    self.executed += operations.len() as u64;
+                    for operation in operations {
+                        let pointer = self.pointer;
+                        match operation {
+                            Operation::Move(direction) => self.shift(direction, 1),
+                            Operation::MoveBy(direction, count) => self.shift(direction, *count),
+                            Operation::Change(Computation::Add) => {
+                                self[pointer] = self[pointer].wrapping_add(1)
+                            }
+                            Operation::Change(Computation::Substract) => {
+                                self[pointer] = self[pointer].wrapping_sub(1)
+                            }
+                            Operation::ChangeBy(Computation::Add, amount) => {
+                                self[pointer] = self[pointer].wrapping_add(*amount)
+                            }
+                            Operation::ChangeBy(Computation::Substract, amount) => {
+                                self[pointer] = self[pointer].wrapping_sub(*amount)
+                            }
+                            Operation::SetZero => self[pointer] = 0,
+                            Operation::SetCell(value) => self[pointer] = *value,
+                            Operation::Scan(direction) => {
+                                while self.get_current() != 0 {
+                                    self.shift(direction, 1);
+                                }
+                            }
+                            Operation::Block { deltas, net_move } => {
+                                self.apply_block(deltas, *net_move)?
+                            }
+                            Operation::Print => self.print()?,
+                            Operation::Read => self.read()?,
+                            Operation::Debug => self.debug()?,
+                        }
+                    }
+                    instructions = next;
+                }
+                AST::Loop(body, next) => {
+                    while self.get_current() != 0 {
+                        self.execute_fast(body)?;
+                    }
+                    instructions = next;
+                }
+                AST::EOF => return Ok(()),
+            }
+        }
+    }
+
+    pub fn run(&mut self, instructions: &AST) -> io::Result<&[u8]> {
+        self.run_checked(instructions, None)
+    }
+
+    /// Runs `instructions`, stopping when `cancel` is set.
+    fn run_checked(
+        &mut self,
+        instructions: &AST,
+        cancel: Option<&AtomicBool>,
+    ) -> io::Result<&[u8]> {
+        let measured = self.timing.is_some() || self.throughput.is_some();
+        let start = if measured { Some(Instant::now()) } else { None };
+        let executed = self.executed;
+        let result = self.run_untimed(instructions, cancel).map(|_| ());
+        if let Some(start) = start {
+            let elapsed = start.elapsed();
+            if let Some(timing) = self.timing.as_mut() {
+                timing.total += elapsed;
+                timing.operations += self.executed - executed;
+            }
+            if let Some((total, operations)) = self.throughput.as_mut() {
+                *total += elapsed;
+                *operations += self.executed - executed;
+            }
+        }
+        result.map(move |_| self.memory.as_slice())
+    }
+
+    fn run_untimed(
+        &mut self,
+        instructions: &AST,
+        cancel: Option<&AtomicBool>,
+    ) -> io::Result<&[u8]> {
+        if self.transactional_output {
+            return self.run_transaction(instructions, cancel);
+        }
+        if let Some(streaming) = self.streaming_tape.as_mut() {
+            streaming.active =
+                !self.ring && self.grid_width.is_none() && instructions.is_forward_only();
+        }
+        if self.is_plain() && cancel.is_none() {
+            return self.run_fast(instructions);
+        }
+
+        if let Some(profile) = self.loop_profile.as_mut() {
+            profile.positions = instructions.loop_positions();
+        }
+        if let Some(limit) = self.loop_limit.as_mut() {
+            limit.positions = instructions.loop_positions();
+        }
+        if let Some(checkpoint) = self.checkpoint.as_mut() {
+            checkpoint.locations = instructions.operation_indices();
+        }
+        if let Some(source_lines) = self.source_lines.as_mut() {
+            source_lines.indices = instructions.operation_indices();
+        }
+
+        let result = self.execute(instructions, cancel);
+
+        if let Some(profile) = self.loop_profile.as_mut() {
+            profile.positions.clear();
+        }
+        if let Some(limit) = self.loop_limit.as_mut() {
+            limit.positions.clear();
+        }
+        if let Some(checkpoint) = self.checkpoint.as_mut() {
+            checkpoint.locations.clear();
+        }
+        if let Some(source_lines) = self.source_lines.as_mut() {
+            source_lines.indices.clear();
+        }
+        result.map(move |_| self.memory.as_slice())
+    }
+
+    fn run_transaction(
+        &mut self,
+        instructions: &AST,
+        cancel: Option<&AtomicBool>,
+    ) -> io::Result<&[u8]> {
+        let buffer = OutputBuffer::new();
+        let output = std::mem::replace(&mut self.output, Box::new(buffer.clone()));
+        self.transactional_output = false;
+        let result = self.run_untimed(instructions, cancel).map(|_| ());
+        self.transactional_output = true;
+        self.output = output;
+
+        result?;
+        self.output.write_all(&buffer.take())?;
+        self.output.flush()?;
+        Ok(self.memory.as_slice())
+    }
+
+    /// Adds the source line of `operation` to `error`, when known.
+    fn locate(&self, operation: &Operation, error: io::Error) -> io::Error {
+        let line = self.source_lines.as_ref().and_then(|source_lines| {
+            let index = source_lines.indices.get(&(operation as *const _))?;
+            source_lines.map.line(*index)
+        });
+        match line {
+            Some(line) => io::Error::new(error.kind(), LineError { line, error }),
+            None => error,
+        }
+    }
+
+    fn execute(&mut self, instructions: &AST, cancel: Option<&AtomicBool>) -> io::Result<()> {
+        match instructions {
+            AST::Instructions(operations, next) => {
+                for op in operations {
+                    if let Some(cancel) = cancel {
+                        check_cancel(cancel, &mut self.cancel_countdown)?;
+                    }
+                    if let Err(error) = self.apply(op) {
+                        return Err(self.locate(op, error));
+                    }
+                }
+                self.execute(next, cancel)
+            }
+            AST::Loop(body, next) => {
+                let mut iterations = 0;
+                while self.get_current() != 0 {
+                    if let Some(cancel) = cancel {
+                        check_cancel(cancel, &mut self.cancel_countdown)?;
+                    }
+                    if let Some(profile) = self.loop_profile.as_mut() {
+                        let position = profile.positions[&(instructions as *const AST)];
+                        *profile.iterations.entry(position).or_insert(0) += 1;
+                    }
+                    if let Some(limit) = &self.loop_limit {
+                        iterations += 1;
+                        if iterations > limit.iterations {
+                            let position = limit.positions[&(instructions as *const AST)];
+                            return Err(RunError::LoopLimitExceeded(position).into());
+                        }
+                    }
+                    self.execute(body, cancel)?;
+                }
+                self.execute(next, cancel)
+            }
+            AST::EOF => Ok(()),
+        }
+    }
+
+    /// Runs `program` until it ends or `cancel` is set, checking it every few thousand
+    /// operations. Cancelled runs fail with [`RunError::Cancelled`].
+    pub fn run_cancellable(&mut self, program: &AST, cancel: &AtomicBool) -> io::Result<&[u8]> {
+        self.cancel_countdown = 0;
+        self.run_checked(program, Some(cancel))
+    }
+
+    /// Applies `operations` in order to the current state, as a straight-line program.
+    pub fn execute_ops(&mut self, operations: &[Operation]) -> io::Result<&[u8]> {
+        for operation in operations {
+            self.apply(operation)?;
+        }
+        Ok(self.memory.as_slice())
+    }
+
+    /// Runs `program` on the cells in `window` only, as if they were the whole tape: the
+    /// program starts on the first cell of the window and cannot move out of it. The tape
+    /// grows to hold the window, and the pointer is restored afterwards. Fails with
+    /// [`InvalidInput`](io::ErrorKind::InvalidInput) on empty windows and on windows past the
+    /// end of a tape that can't grow.
+    pub fn run_subroutine(&mut self, program: &AST, window: Range<usize>) -> io::Result<()> {
+        if window.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "subroutine window needs at least one cell",
+            ));
+        }
+        self.memory.grow(window.end);
+        if window.end > self.memory.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("subroutine window {:?} is past the end of the tape", window),
+            ));
+        }
+
+        let cells = FixedTape(self.memory.as_slice()[window.clone()].into());
+        let tape = std::mem::replace(&mut self.memory, Box::new(cells));
+        let pointer = std::mem::replace(&mut self.pointer, 0);
+        let result = self.run(program).map(|_| ());
+
+        let cells = std::mem::replace(&mut self.memory, tape);
+        self.pointer = pointer;
+        for (index, &value) in window.zip(cells.as_slice()) {
+            self.memory[index] = value;
+        }
+        result
+    }
+
+    /// Runs `program` on `input`, returning what it printed and the final tape. The input and
+    /// output of the machine are restored afterwards.
+    pub fn run_capturing(&mut self, program: &AST, input: &[u8]) -> io::Result<(Vec<u8>, Vec<u8>)> {
+        let output = OutputBuffer::new();
+        let previous_input =
+            std::mem::replace(&mut self.input, Box::new(io::Cursor::new(input.to_vec())));
+        let previous_output = std::mem::replace(&mut self.output, Box::new(output.clone()));
+        let result = self.run(program).map(|_| ());
+        self.input = previous_input;
+        self.output = previous_output;
+
+        result?;
+        Ok((output.take(), self.tape().to_vec()))
+    }
+
+    /// Runs `program` with the tape limited to `tape_limit` cells, reporting the resources it
+    /// used. Reaching the limit stops the run without failing, other errors are returned.
+    pub fn run_measured(&mut self, program: &AST, tape_limit: usize) -> io::Result<Measurement> {
+        let executed = self.executed;
+        let previous = self.tape_limit.replace(tape_limit);
+        self.peak_pointer = Some(self.discarded() + self.pointer);
+        let result = self.run(program).map(|_| ());
+        self.tape_limit = previous;
+        let peak = self.peak_pointer.take().unwrap_or_default();
+
+        let limit_reached = match result {
+            Ok(()) => false,
+            Err(error) if run_error(&error) == Some(&RunError::TapeLimitReached(tape_limit)) => {
+                true
+            }
+            Err(error) => return Err(error),
+        };
+        Ok(Measurement {
+            peak_tape: peak + 1,
+            executed: self.executed - executed,
+            limit_reached,
+        })
+    }
+
+    /// Runs `program`, failing with [`RunError::UnbalancedPointer`] unless it leaves the
+    /// pointer on the first cell.
+    pub fn run_expect_clean(&mut self, program: &AST) -> io::Result<&[u8]> {
+        self.run(program)?;
+        if self.pointer != 0 {
+            return Err(RunError::UnbalancedPointer(self.pointer).into());
+        }
+        Ok(self.memory.as_slice())
+    }
+
+    pub fn stepper<'a>(&'a mut self, program: &'a AST) -> Stepper<'a> {
+        Stepper::new(self, program)
+    }
+
+    /// Finishes a run of `program` interrupted at `snapshot`, typically on a machine restored
+    /// with [`from_snapshot`](MachineState::from_snapshot).
+    pub fn resume(&mut self, program: &AST, snapshot: &Snapshot) -> io::Result<&[u8]> {
+        let location = snapshot.location.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "snapshot has no location")
+        })?;
+        let mut stepper = Stepper::resume(self, program, location).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "snapshot does not match the program",
+            )
+        })?;
+        while stepper.step()?.is_some() {}
+
+        Ok(self.memory.as_slice())
+    }
+
+    /// Runs `program` lazily, yielding each printed byte instead of writing it to the output.
+    pub fn run_yielding<'a>(&'a mut self, program: &'a AST) -> OutputBytes<'a> {
+        OutputBytes::new(self.stepper(program))
+    }
+
+    /// Renders the tape, as by `Display`, after each of the first `limit` operations of
+    /// `program`, run from the current state on a sandboxed copy of the machine, whose reads
+    /// give zeros. The copy keeps the ring, 2D, saturating, grow chunk, random initialization,
+    /// tape limit and label settings, and a ring stays fixed size. Other tapes are copied to a
+    /// growable tape, and all the other modes are off.
+    pub fn run_trace_states(&self, program: &AST, limit: usize) -> io::Result<Vec<String>> {
+        let memory: Box<dyn Tape> = if self.ring {
+            Box::new(FixedTape(self.tape().into()))
+        } else {
+            Box::new(DefaultTape::from(self.tape()))
+        };
+        let mut machine = MachineState {
+            pointer: self.pointer,
+            memory,
+            saturating: self.saturating,
+            ring: self.ring,
+            ring_clamped: self.ring_clamped,
+            ring_display: self.ring_display,
+            grow_chunk: self.grow_chunk,
+            grid_width: self.grid_width,
+            labels: self.labels.clone(),
+            random_init: self.random_init.clone(),
+            tape_limit: self.tape_limit,
+            ..MachineState::sandboxed()
+        };
+        let mut stepper = machine.stepper(program);
+
+        let mut states = Vec::new();
+        while states.len() < limit && stepper.step()?.is_some() {
+            states.push(stepper.machine().to_string());
+        }
+        Ok(states)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Computation;
+    use super::Direction;
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    struct ArrayTape([u8; 3]);
+
+    impl Index<usize> for ArrayTape {
+        type Output = u8;
+
+        fn index(&self, index: usize) -> &u8 {
+            &self.0[index]
+        }
+    }
+
+    impl IndexMut<usize> for ArrayTape {
+        fn index_mut(&mut self, index: usize) -> &mut u8 {
+            &mut self.0[index]
+        }
+    }
+
+    impl Tape for ArrayTape {
+        fn len(&self) -> usize {
+            self.0.len()
+        }
+
+        fn grow(&mut self, _len: usize) {}
+
+        fn as_slice(&self) -> &[u8] {
+            &self.0
+        }
+    }
+
+    #[test]
+    fn command_set() {
+        for c in COMMANDS {
+            assert_eq!(Operation::from(*c).is_none(), matches!(c, '[' | ']'));
+        }
+        assert!(is_command('#'));
+        assert!(!is_command('a'));
+        assert!(!is_command('^'));
+    }
+
+    #[test]
+    fn operation_valid() {
+        assert_eq!(
+            Operation::from(Operation::Debug.value()),
+            Some(Operation::Debug)
+        );
+
+        assert_eq!(
+            Operation::from(Operation::Print.value()),
+            Some(Operation::Print)
+        );
+
+        assert_eq!(
+            Operation::from(Operation::Read.value()),
+            Some(Operation::Read)
+        );
+
+        assert_eq!(
+            Operation::from(Operation::Change(Computation::Add).value()),
+            Some(Operation::Change(Computation::Add))
+        );
+
+        assert_eq!(
+            Operation::from(Operation::Change(Computation::Substract).value()),
+            Some(Operation::Change(Computation::Substract))
+        );
+
+        assert_eq!(
+            Operation::from(Operation::Move(Direction::Left).value()),
+            Some(Operation::Move(Direction::Left))
+        );
+
+        assert_eq!(
+            Operation::from(Operation::Move(Direction::Right).value()),
+            Some(Operation::Move(Direction::Right))
+        );
+    }
+
+    #[test]
+    fn change_value() {
+        let mut machine = MachineState::new();
+
+        machine.change(&Computation::Add);
+
+        assert_eq!(machine.get_current(), 1);
+
+        machine.change(&Computation::Substract);
+
+        assert_eq!(machine.get_current(), 0);
+    }
+
+    #[test]
+    fn move_ptr() {
+        let mut machine = MachineState::new();
+
+        machine.pointer_move(&Direction::Right);
+        machine.change(&Computation::Add);
+
+        assert_eq!(machine[1], 1);
+
+        machine.pointer_move(&Direction::Left);
+        machine.change(&Computation::Add);
+
+        assert_eq!(machine[0], 1);
+    }
+
+    #[test]
+    fn change_overflow() {
+        let mut machine = MachineState::new();
+
+        machine.change(&Computation::Substract);
+
+        assert_eq!(machine.get_current(), 255);
+
+        machine.change(&Computation::Add);
+
+        assert_eq!(machine.get_current(), 0);
+    }
+
+    #[test]
+    fn move_callback() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let moves = Rc::new(RefCell::new(Vec::new()));
+        let recorded = Rc::clone(&moves);
+        let mut machine =
+            MachineState::new().on_move(move |old, new| recorded.borrow_mut().push((old, new)));
+
+        machine.pointer_move(&Direction::Right);
+        machine.pointer_move(&Direction::Right);
+        machine.pointer_move(&Direction::Left);
+
+        assert_eq!(*moves.borrow(), vec![(0, 1), (1, 2), (2, 1)]);
+    }
+
+    #[test]
+    fn sandboxed_io() {
+        let mut machine = MachineState::sandboxed();
+
+        let ast = AST::from_string(String::from("+++.#>+++,"));
+        let memory = machine.run(&ast).expect("Execution failed");
+
+        assert_eq!(memory, [3, 0]);
+    }
+
+    #[test]
+    fn separate_debug_output() {
+        let output = OutputBuffer::new();
+        let debug = OutputBuffer::new();
+        let mut machine = MachineState::sandboxed()
+            .with_output(output.clone())
+            .with_debug_output(debug.clone());
+
+        let ast = AST::from_string(String::from("++++++[>++++++++<-]>.#"));
+        machine.run(&ast).expect("Execution failed");
+
+        assert_eq!(output.contents(), b"0");
+        assert_eq!(debug.contents(), b" 00    30 < \n");
+    }
+
+    #[test]
+    fn quiet_debug() {
+        let debug = OutputBuffer::new();
+        let mut machine = MachineState::sandboxed()
+            .with_debug_output(debug.clone())
+            .with_debug_snapshots();
+
+        let ast = AST::from_string(String::from("++#>+++[-#]"));
+        machine.run(&ast).expect("Execution failed");
+
+        let states: Vec<(usize, Vec<u8>)> = machine
+            .debug_snapshots()
+            .iter()
+            .map(|snapshot| (snapshot.pointer, snapshot.tape.clone()))
+            .collect();
+        assert_eq!(
+            states,
+            vec![
+                (0, vec![2]),
+                (1, vec![2, 2]),
+                (1, vec![2, 1]),
+                (1, vec![2, 0])
+            ]
+        );
+        assert!(debug.contents().is_empty());
+        assert!(MachineState::sandboxed().debug_snapshots().is_empty());
+    }
+
+    #[test]
+    fn output_byte_callbacks() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let bells = Rc::new(RefCell::new(0));
+        let counter = bells.clone();
+        let mut machine = MachineState::sandboxed()
+            .on_output_byte(0x07, move || *counter.borrow_mut() += 1)
+            .on_output_byte(b'A', || panic!("no `A` is printed"));
+
+        let ast = AST::from_string(String::from("+++++++..+.-."));
+        machine.run(&ast).expect("Execution failed");
+
+        assert_eq!(*bells.borrow(), 3);
+    }
+
+    #[test]
+    fn loop_iteration_limit() {
+        let ast = AST::from_string(String::from("+++[-]>++[>+++[-]<-]"));
+        MachineState::sandboxed()
+            .with_loop_iteration_limit(3)
+            .run(&ast)
+            .expect("Execution failed");
+
+        let error = MachineState::sandboxed()
+            .with_loop_iteration_limit(3)
+            .run(&AST::from_string(String::from("+[>++++[-]<-]>+[+]")))
+            .unwrap_err();
+        assert_eq!(
+            run_error(&error),
+            Some(&RunError::LoopLimitExceeded(Position { offset: 7 }))
+        );
+    }
+
+    #[test]
+    fn transactional_output() {
+        let output = OutputBuffer::new();
+        let mut machine = MachineState::sandboxed()
+            .with_output(output.clone())
+            .with_output_limit(3)
+            .with_transactional_output();
+
+        machine
+            .run(&AST::from_string(String::from("+.+.")))
+            .expect("Execution failed");
+        assert_eq!(output.contents(), [1, 2]);
+
+        assert!(machine
+            .run(&AST::from_string(String::from(".+.+.")))
+            .is_err());
+        assert_eq!(output.contents(), [1, 2]);
+    }
+
+    #[test]
+    fn tcp_stream() {
+        use std::net::{Shutdown, TcpListener};
+        use std::thread;
+
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Could not bind");
+        let address = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).expect("Could not connect");
+            let mut prompt = [0; 1];
+            stream.read_exact(&mut prompt).unwrap();
+            stream.write_all(b"ab").unwrap();
+            stream.shutdown(Shutdown::Write).unwrap();
+
+            let mut answer = Vec::new();
+            stream.read_to_end(&mut answer).unwrap();
+            (prompt, answer)
+        });
+
+        let (stream, _) = listener.accept().expect("No connection");
+        let mut machine = MachineState::sandboxed()
+            .with_tcp_stream(stream)
+            .expect("Could not clone stream");
+        machine
+            .run(&AST::from_string(String::from(
+                "++++++[>++++++++++<-]>+++.,[+.,]",
+            )))
+            .expect("Execution failed");
+        drop(machine);
+
+        let (prompt, answer) = client.join().unwrap();
+        assert_eq!(&prompt, b"?");
+        assert_eq!(answer, b"bc");
+    }
+
+    #[test]
+    fn capturing_run() {
+        let mut machine = MachineState::sandboxed();
+        let program = AST::from_string(String::from(",[+.>,]"));
+
+        let (output, tape) = machine
+            .run_capturing(&program, b"abc")
+            .expect("Execution failed");
+        assert_eq!(output, b"bcd");
+        assert_eq!(tape, b"bcd\0");
+
+        let (output, _) = machine
+            .run_capturing(&program, b"")
+            .expect("Execution failed");
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn blocks() {
+        let block = AST::Instructions(
+            vec![
+                Operation::Change(Computation::Add),
+                Operation::Block {
+                    deltas: vec![(0, -2), (2, 300 % 256), (3, 0)],
+                    net_move: 1,
+                },
+            ],
+            Box::from(AST::EOF),
+        );
+        assert_eq!(block.to_string(), format!("+-->>{}><<", "+".repeat(44)));
+        for (deltas, net_move) in [
+            (vec![(0, -2), (2, 44)], 1),
+            (vec![(0, 0), (2, 1)], 0),
+            (vec![(-1, 3)], 0),
+            (vec![], -2),
+            (vec![], 0),
+        ] {
+            let operation = Operation::Block { deltas, net_move };
+            let first = operation.to_string().chars().next().unwrap_or('>');
+            assert_eq!(operation.value(), first, "{:?}", operation);
+        }
+
+        for machine in [
+            MachineState::sandboxed(),
+            MachineState::sandboxed().with_timing(),
+        ] {
+            let mut machine = machine;
+            assert_eq!(machine.run(&block).unwrap(), [255, 0, 44, 0]);
+            assert_eq!(machine.pointer(), 1);
+            assert_eq!(machine.executed(), 2);
+        }
+
+        let error = MachineState::sandboxed()
+            .with_tape_limit(3)
+            .run(&block)
+            .unwrap_err();
+        assert_eq!(run_error(&error), Some(&RunError::TapeLimitReached(3)));
+    }
+
+    #[test]
+    fn random_input() {
+        let run = |seed| {
+            let mut machine = MachineState::sandboxed().with_random_input(seed);
+            let ast = AST::from_string(String::from(",>,>,>,>,>,>,>,"));
+            machine.run(&ast).expect("Execution failed").to_vec()
+        };
+
+        let memory = run(7);
+        assert_eq!(memory.len(), 8);
+        assert_eq!(memory, run(7));
+        assert_ne!(memory, run(8));
+
+        // Reads never reach the end of the input.
+        let mut machine = MachineState::sandboxed().with_random_input(7);
+        let ast = AST::from_string(String::from("+[,]"));
+        machine.run(&ast).expect("Execution failed");
+        assert_eq!(machine.get_current(), 0);
+    }
+
+    #[test]
+    fn measured_run() {
+        let mut machine = MachineState::sandboxed();
+        let measurement = machine
+            .run_measured(&AST::from_string(String::from("+>+>+[-]")), 8)
+            .expect("Execution failed");
+        assert_eq!(
+            measurement,
+            Measurement {
+                peak_tape: 3,
+                executed: 6,
+                limit_reached: false
+            }
+        );
+
+        let mut machine = MachineState::sandboxed();
+        let measurement = machine
+            .run_measured(&AST::from_string(String::from("+[>+]")), 8)
+            .expect("Execution failed");
+        assert_eq!(measurement.peak_tape, 8);
+        assert!(measurement.limit_reached);
+        assert_eq!(machine.pointer(), 7);
+
+        let mut machine = MachineState::sandboxed().with_grow_chunk(64);
+        machine
+            .run(&AST::from_string(String::from(">>>>>>>>>><<<<<<<<<<")))
+            .expect("Execution failed");
+        let measurement = machine
+            .run_measured(&AST::from_string(String::from(">>>+<")), 8)
+            .expect("Execution failed");
+        assert_eq!(measurement.peak_tape, 4);
+
+        let error = MachineState::sandboxed()
+            .with_tape_limit(2)
+            .run(&AST::from_string(String::from(">>")))
+            .unwrap_err();
+        assert_eq!(run_error(&error), Some(&RunError::TapeLimitReached(2)));
+    }
+
+    #[test]
+    fn streaming_tape() {
+        let input = vec![b'a'; 10_000];
+        let increment = AST::from_string(String::from(",[+>,]"));
+        let mut reference = MachineState::sandboxed().with_input_bytes(&input);
+        reference.run(&increment).expect("Execution failed");
+
+        let mut machine = MachineState::sandboxed()
+            .with_input_bytes(&input)
+            .with_streaming_tape();
+        machine.run(&increment).expect("Execution failed");
+
+        assert!(machine.discarded() > 0);
+        assert!(machine.tape().len() <= STREAMING_WINDOW);
+        assert_eq!(machine.discarded() + machine.pointer(), reference.pointer());
+        assert_eq!(machine.tape(), &reference.tape()[machine.discarded()..]);
+
+        let mut machine = MachineState::sandboxed()
+            .with_input_bytes(&input)
+            .with_streaming_tape();
+        machine
+            .run(&AST::from_string(String::from(",[+>,]<")))
+            .expect("Execution failed");
+        assert_eq!(machine.discarded(), 0);
+        assert_eq!(machine.tape().len(), input.len() + 1);
+
+        let error = MachineState::sandboxed()
+            .with_input_bytes(&input)
+            .with_streaming_tape()
+            .with_tape_limit(5000)
+            .run(&increment)
+            .unwrap_err();
+        assert_eq!(run_error(&error), Some(&RunError::TapeLimitReached(5000)));
+
+        let sweep = AST::from_string(">".repeat(5000) + "+");
+        let mut fixed =
+            MachineState::with_tape(FixedTape(vec![0; 6000].into())).with_streaming_tape();
+        fixed.run(&sweep).expect("Execution failed");
+        assert_eq!(fixed.discarded(), 0);
+        assert_eq!(fixed.tape().len(), 6000);
+        assert_eq!(fixed.tape()[5000], 1);
+
+        let mut machine = MachineState::sandboxed().with_streaming_tape();
+        machine
+            .run_subroutine(&sweep, 10..6010)
+            .expect("Execution failed");
+        assert_eq!(machine.discarded(), 0);
+        assert_eq!(machine.tape().len(), 6010);
+        assert_eq!(machine.tape()[5010], 1);
+    }
+
+    #[test]
+    fn timing() {
+        let mut machine = MachineState::sandboxed()
+            .with_input_bytes(b"a")
+            .with_timing();
+        let ast = AST::from_string(String::from("++[->+<],."));
+        machine.run(&ast).expect("Execution failed");
+
+        let timing = machine.timing().expect("timing is enabled");
+        assert!(timing.moves + timing.changes + timing.prints + timing.reads > Duration::ZERO);
+        assert_eq!(MachineState::sandboxed().timing(), None);
+
+        let mut machine = MachineState::sandboxed().with_timing();
+        machine
+            .run(&AST::from_string(String::from("##")))
+            .expect("Execution failed");
+        let timing = machine.timing().expect("timing is enabled");
+        assert_eq!(
+            timing.moves + timing.changes + timing.prints + timing.reads,
+            Duration::ZERO
+        );
+    }
+
+    #[test]
+    fn ops_per_second() {
+        let mut machine = MachineState::sandboxed().with_timing();
+        assert_eq!(machine.ops_per_second(), None);
+        machine
+            .run(&AST::from_string(String::from("++++[->++++<]")))
+            .expect("Execution failed");
+
+        let timing = machine.timing().expect("timing is enabled");
+        assert_eq!(timing.operations, machine.executed());
+        assert!(timing.total >= timing.moves + timing.changes);
+        assert!(machine.ops_per_second().expect("time was measured") > 0.0);
+        assert_eq!(MachineState::sandboxed().ops_per_second(), None);
+
+        let mut machine = MachineState::sandboxed().with_throughput();
+        assert!(machine.is_plain());
+        assert_eq!(machine.ops_per_second(), None);
+        machine
+            .run(&AST::from_string("+".repeat(10_000) + "[-]"))
+            .expect("Execution failed");
+        assert!(machine.ops_per_second().expect("time was measured") > 0.0);
+    }
+
+    #[test]
+    fn subroutine() {
+        let mut machine = MachineState::sandboxed();
+        machine
+            .run(&AST::from_string(String::from("+>++>+++<")))
+            .expect("Execution failed");
+
+        // Moves the first cell of the window into the next one, with no way of reaching the
+        // cells around it.
+        let transfer = AST::from_string(String::from("<<[->+<]>>>>+"));
+        machine
+            .run_subroutine(&transfer, 1..3)
+            .expect("Execution failed");
+
+        assert_eq!(machine.tape(), [1, 0, 6]);
+        assert_eq!(machine.pointer(), 1);
+
+        machine
+            .run_subroutine(&transfer, 4..6)
+            .expect("Execution failed");
+        assert_eq!(machine.tape(), [1, 0, 6, 0, 0, 1]);
+
+        let error = machine.run_subroutine(&transfer, 2..2).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        let mut fixed = MachineState::with_ring_tape(4);
+        let error = fixed.run_subroutine(&transfer, 2..5).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(fixed.tape(), [0; 4]);
+    }
+
+    #[test]
+    fn trimmed_debug() {
+        let debug = OutputBuffer::new();
+        let mut machine = MachineState::sandboxed()
+            .with_debug_output(debug.clone())
+            .with_trimmed_debug();
+
+        let ast = AST::from_string(String::from(">>>++>>+>>#"));
+        machine.run(&ast).expect("Execution failed");
+
+        assert_eq!(machine.used_range(), 3..8);
+        assert_eq!(
+            String::from_utf8(debug.contents()).unwrap(),
+            " 02    00    01    00    00 < \n"
+        );
+        assert_eq!(MachineState::sandboxed().used_range(), 0..1);
+    }
+
+    #[test]
+    fn trace_states() {
+        let mut machine = MachineState::sandboxed();
+        machine
+            .run(&AST::from_string(String::from("++")))
+            .expect("Execution failed");
+
+        let program = AST::from_string(String::from("[->+<]"));
+        let states = machine.run_trace_states(&program, 4).unwrap();
+        assert_eq!(
+            states,
+            [" 01 < ", " 01    00 < ", " 01    01 < ", " 01 <  01   ",]
+        );
+        assert_eq!(machine.run_trace_states(&program, 100).unwrap().len(), 8);
+        assert_eq!(machine.tape(), [2]);
+
+        let ring = MachineState::with_ring_tape(2).with_ring_display();
+        let states = ring
+            .run_trace_states(&AST::from_string(String::from("+>>+")), 4)
+            .unwrap();
+        assert_eq!(
+            states,
+            [
+                " 01 <  00   -> 01",
+                " 01    00 < -> 01",
+                " 01 <  00   -> 01",
+                " 02 <  00   -> 02",
+            ]
+        );
+    }
+
+    #[test]
+    fn debug_window() {
+        let debug = OutputBuffer::new();
+        let mut machine = MachineState::sandboxed()
+            .with_debug_output(debug.clone())
+            .with_debug_window(2);
+
+        let ast = AST::from_string(String::from("+>++>+++>>>>+<<#<<<#>>>>>>>#"));
+        machine.run(&ast).expect("Execution failed");
+
+        assert_eq!(
+            String::from_utf8(debug.contents()).unwrap(),
+            " 03    00    00 <  00    01   \n \
+             01    02 <  03    00   \n \
+             01    00    00 < \n"
+        );
+    }
+
+    #[test]
+    fn newline_translation() {
+        let program = AST::from_string(String::from("++++++++++.>+++++++[<+++++++>-]<++."));
+
+        let raw = OutputBuffer::new();
+        let mut machine = MachineState::sandboxed().with_output(raw.clone());
+        machine.run(&program).expect("Execution failed");
+        assert_eq!(raw.contents(), b"\n=");
+
+        let translated = OutputBuffer::new();
+        let mut machine = MachineState::sandboxed()
+            .with_output(translated.clone())
+            .with_newline_translation();
+        machine.run(&program).expect("Execution failed");
+        assert_eq!(translated.contents(), [NEWLINE, b"="].concat());
+    }
+
+    #[test]
+    fn hex_output() {
+        let output = OutputBuffer::new();
+        let mut machine = MachineState::sandboxed()
+            .with_output(output.clone())
+            .with_output_mode(OutputMode::Hex);
+
+        let program = AST::from_string(String::from(".-.++++++++++++.+[>+<++]>."));
+        machine.run(&program).expect("Execution failed");
+
+        assert_eq!(output.contents(), b"00 FF 0B 7A");
+    }
+
+    #[test]
+    fn unicode_output() {
+        let output = OutputBuffer::new();
+        let mut machine = MachineState::sandboxed()
+            .with_output(output.clone())
+            .with_output_mode(OutputMode::Unicode);
+
+        let program = AST::from_string(String::from("++++++[>++++++++<-]>+.[-]-."));
+        machine.run(&program).expect("Execution failed");
+
+        assert_eq!(String::from_utf8(output.contents()).unwrap(), "1ÿ");
+    }
+
+    #[test]
+    fn loop_hotspots() {
+        let mut machine = MachineState::sandboxed().with_loop_profiling();
+
+        let ast = AST::from_string(String::from("+++[>++[-]<-] >+[-]"));
+        machine.run(&ast).expect("Execution failed");
+
+        assert_eq!(
+            machine.loop_hotspots(),
+            vec![
+                (Position { offset: 7 }, 6),
+                (Position { offset: 3 }, 3),
+                (Position { offset: 15 }, 1)
+            ]
+        );
+    }
+
+    struct Silent;
+
+    impl Read for Silent {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            loop {
+                std::thread::park();
+            }
+        }
+    }
+
+    struct CountingReader {
+        data: io::Cursor<Vec<u8>>,
+        reads: Arc<Mutex<usize>>,
+    }
+
+    impl Read for CountingReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            *self.reads.lock().unwrap() += 1;
+            self.data.read(buf)
+        }
+    }
+
+    #[test]
+    fn buffered_input() {
+        let reads = Arc::new(Mutex::new(0));
+        let output = OutputBuffer::new();
+        let mut machine = MachineState::sandboxed()
+            .with_input(CountingReader {
+                data: io::Cursor::new(vec![b'x'; 1000]),
+                reads: Arc::clone(&reads),
+            })
+            .with_output(output.clone());
+
+        machine
+            .run(&AST::from_string(String::from(",[.,]")))
+            .expect("Execution failed");
+
+        assert_eq!(output.contents(), vec![b'x'; 1000]);
+        assert!(*reads.lock().unwrap() <= 2);
+    }
+
+    #[test]
+    fn nonblocking_input() {
+        let mut machine = MachineState::sandboxed()
+            .with_input(Silent)
+            .with_nonblocking_input();
+
+        let ast = AST::from_string(String::from("+,>+"));
+        let memory = machine.run(&ast).expect("Execution failed");
+
+        assert_eq!(memory, [0, 1]);
+    }
+
+    #[test]
+    fn read_timeout() {
+        let mut machine = MachineState::sandboxed()
+            .with_input(io::Cursor::new(vec![7, 8]))
+            .with_read_timeout(Duration::from_secs(5));
+
+        let ast = AST::from_string(String::from(",>,>+,"));
+        let memory = machine.run(&ast).expect("Execution failed");
+        assert_eq!(memory, [7, 8, 0]);
+
+        let mut machine = MachineState::sandboxed()
+            .with_input(Silent)
+            .with_read_timeout(Duration::from_millis(10));
+
+        let ast = AST::from_string(String::from("+,"));
+        let memory = machine.run(&ast).expect("Execution failed");
+        assert_eq!(memory, [0]);
+    }
+
+    #[test]
+    fn bang_input_program() {
+        let (program, input) = AST::from_with_bang_input(",>,>,!ab");
+        let mut machine = MachineState::sandboxed().with_input_bytes(&input);
+
+        let memory = machine.run(&program).expect("Execution failed");
+
+        assert_eq!(memory, [b'a', b'b', 0]);
+    }
+
+    #[test]
+    fn repeated_moves_accumulate() {
+        let mut machine = MachineState::sandboxed();
+
+        let ast = AST::from_string(String::from(">+")).repeat(3);
+        let memory = machine.run(&ast).expect("Execution failed");
+
+        assert_eq!(memory, [0, 1, 1, 1]);
+    }
+
+    #[test]
+    fn start_pointer() {
+        let mut machine = MachineState::sandboxed().with_start_pointer(3);
+        assert_eq!(machine.tape(), [0; 4]);
+
+        let ast = AST::from_string(String::from("+<++"));
+        assert_eq!(machine.run(&ast).expect("Execution failed"), [0, 0, 2, 1]);
+    }
+
+    #[test]
+    #[should_panic(expected = "past the end of the tape")]
+    fn start_pointer_out_of_tape() {
+        let _ = MachineState::with_tape(ArrayTape([0; 3])).with_start_pointer(3);
+    }
+
+    #[test]
+    fn fixed_size_tape() {
+        let mut machine = MachineState::with_tape(ArrayTape([0; 3]));
+
+        let ast = AST::from_string(String::from("+>+>+>+>+"));
+        let memory = machine.run(&ast).expect("Execution failed");
+
+        assert_eq!(memory, [1, 1, 3]);
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn mmap_tape() {
+        let path = std::env::temp_dir().join(format!("rebf-mmap-{}.bin", std::process::id()));
+
+        let mut machine = MachineState::with_mmap_tape(&path, 4).expect("Mapping failed");
+        let ast = AST::from_string(String::from("+>++>+++"));
+        let memory = machine.run(&ast).expect("Execution failed");
+        assert_eq!(memory, [1, 2, 3, 0]);
+        drop(machine);
+
+        assert_eq!(std::fs::read(&path).unwrap(), vec![1, 2, 3, 0]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn dump_tape() {
+        let path = std::env::temp_dir().join(format!("rebf-dump-{}.bin", std::process::id()));
+        let mut machine = MachineState::sandboxed();
+
+        let ast = AST::from_string(String::from("+>++>-"));
+        machine.run(&ast).expect("Execution failed");
+        machine.dump_tape(&path).expect("Dump failed");
+
+        assert_eq!(std::fs::read(&path).unwrap(), vec![1, 2, 255]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn checkpoint_and_resume() {
+        let program = AST::from_string(String::from("++++[>+++[>++<-]<-]>>."));
+        let checkpoints = Arc::new(Mutex::new(Vec::new()));
+
+        let recorded = Arc::clone(&checkpoints);
+        let mut machine = MachineState::sandboxed().with_checkpoint(10, move |snapshot| {
+            recorded.lock().unwrap().push(snapshot.clone())
+        });
+        let expected = machine.run(&program).expect("Execution failed").to_vec();
+
+        let checkpoints = checkpoints.lock().unwrap();
+        assert_eq!(checkpoints.len() as u64, machine.executed() / 10);
+
+        for snapshot in checkpoints.iter() {
+            let snapshot = Snapshot::from_bytes(&snapshot.to_bytes()).unwrap();
+            let mut restored = MachineState::from_snapshot(&snapshot).with_output(io::sink());
+
+            let memory = restored.resume(&program, &snapshot).expect("Resume failed");
+
+            assert_eq!(memory, expected.as_slice());
+            assert_eq!(restored.executed(), machine.executed());
+        }
+    }
+
+    #[test]
+    fn uninit_warnings() {
+        let warnings = OutputBuffer::new();
+        let mut machine = MachineState::sandboxed()
+            .with_uninit_warnings()
+            .with_warning_output(warnings.clone());
+
+        let ast = AST::from_string(String::from(",.>++.>,.>."));
+        machine.run(&ast).expect("Execution failed");
+
+        assert_eq!(
+            String::from_utf8(warnings.contents()).unwrap(),
+            "warning: `+` reads uninitialized cell 1\n\
+             warning: `.` reads uninitialized cell 3\n"
+        );
+    }
+
+    #[test]
+    fn saturating_cells() {
+        let mut machine = MachineState::sandboxed().with_saturating_cells();
+
+        let ast = AST::from_string(String::from("->") + &"+".repeat(300) + ">++");
+        let memory = machine.run(&ast).expect("Execution failed").to_vec();
+        assert_eq!(memory, [0, 255, 2]);
+
+        let ast = AST::from_string(String::from("<<--")).optimized();
+        assert_eq!(machine.run(&ast).expect("Execution failed"), [0, 255, 2]);
+    }
+
+    #[test]
+    fn written_cells() {
+        let warnings = OutputBuffer::new();
+        let mut machine = MachineState::sandboxed()
+            .with_warning_output(warnings.clone())
+            .with_write_tracking();
+        machine
+            .run(&AST::from_string(String::from("+>>>.>-<<+[-]>>>>>")))
+            .expect("Execution failed");
+
+        assert_eq!(machine.tape().len(), 8);
+        assert_eq!(machine.written_cells(), 3);
+        assert!(warnings.contents().is_empty());
+        assert_eq!(MachineState::sandboxed().written_cells(), 0);
+    }
+
+    #[test]
+    fn grow_chunk() {
+        let mut machine = MachineState::sandboxed().with_grow_chunk(16);
+        machine
+            .run(&AST::from_string(String::from(">+>>>>>>>>>>>>>>>>+")))
+            .expect("Execution failed");
+
+        assert_eq!(machine.tape().len(), 33);
+        assert_eq!(machine.pointer(), 17);
+        assert_eq!(machine.tape()[1], 1);
+        assert_eq!(machine.tape()[17], 1);
+        assert_eq!(
+            machine
+                .tape()
+                .iter()
+                .map(|&cell| cell as usize)
+                .sum::<usize>(),
+            2
+        );
+    }
+
+    #[test]
+    fn grid_tape() {
+        let mut machine = MachineState::sandboxed().with_2d();
+        machine
+            .run(&AST::from_2d("+>++v+++<^^-vv>>+"))
+            .expect("Execution failed");
+
+        assert_eq!(machine.grid_position(), Some((2, 2)));
+        assert_eq!(machine.tape(), [0, 2, 0, 0, 0, 3, 0, 0, 0, 0, 1, 0]);
+
+        let mut flat = MachineState::sandboxed();
+        flat.run(&AST::from_2d("+v>+^")).expect("Execution failed");
+        assert_eq!(flat.tape(), [1, 1]);
+        assert_eq!(flat.grid_position(), None);
+
+        let mut ring = MachineState::with_ring_tape(4).with_2d();
+        ring.run(&AST::from_2d("v+>>>>>+"))
+            .expect("Execution failed");
+        assert_eq!(ring.tape(), [1, 0, 0, 1]);
+        assert_eq!(ring.grid_position(), Some((0, 3)));
+
+        let error = MachineState::sandboxed()
+            .with_2d()
+            .with_tape_limit(2)
+            .run(&AST::from_2d("+vv"))
+            .unwrap_err();
+        assert_eq!(run_error(&error), Some(&RunError::TapeLimitReached(2)));
+
+        let error = MachineState::sandboxed()
+            .with_2d()
+            .with_tape_limit(3)
+            .run(&AST::from_2d("v>"))
+            .unwrap_err();
+        assert_eq!(run_error(&error), Some(&RunError::TapeLimitReached(3)));
+    }
+
+    #[test]
+    fn ring_tape() {
+        let mut machine = MachineState::with_ring_tape(4);
+        machine
+            .run(&AST::from_string(String::from("+++>>>>+<<<<<<<<-")))
+            .expect("Execution failed");
+        assert_eq!(machine.tape(), [3, 0, 0, 0]);
+        assert_eq!(machine.pointer(), 0);
+
+        machine
+            .run(&AST::from_string(String::from("<+>>+")))
+            .expect("Execution failed");
+        assert_eq!(machine.tape(), [3, 1, 0, 1]);
+        assert_eq!(machine.pointer(), 1);
+
+        let optimized = AST::from_string(String::from(">>>>>>+<<<<<<<+")).optimized();
+        let mut machine = MachineState::with_ring_tape(4);
+        machine.run(&optimized).expect("Execution failed");
+        assert_eq!(machine.tape(), [0, 0, 1, 1]);
+
+        let mut single = MachineState::with_ring_tape(1);
+        single
+            .run(&AST::from_string(String::from("+>+<<+")))
+            .expect("Execution failed");
+        assert_eq!(single.tape(), [3]);
+        assert_eq!(single.pointer(), 0);
+
+        let mut clamped = MachineState::with_ring_tape(4).with_ring_clamping();
+        clamped
+            .run(&AST::from_string(String::from("+>>>>+<<<<<<+")))
+            .expect("Execution failed");
+        assert_eq!(clamped.tape(), [2, 0, 0, 1]);
+        assert_eq!(clamped.pointer(), 0);
+    }
+
+    #[test]
+    fn ring_display() {
+        let mut machine = MachineState::with_ring_tape(3).with_ring_display();
+        machine
+            .run(&AST::from_string(String::from("+>>>++")))
+            .expect("Execution failed");
+        assert_eq!(machine.to_string(), " 03 <  00    00   -> 03");
+
+        let flat = MachineState::with_ring_tape(3);
+        assert_eq!(flat.to_string(), " 00 <  00    00   ");
+    }
+
+    #[test]
+    fn fast_path() {
+        let source = std::fs::read_to_string("tests/hello_world.bf").unwrap();
+        let program = AST::from_string(source);
+
+        let output = OutputBuffer::new();
+        let mut machine = MachineState::sandboxed().with_output(output.clone());
+        let expected = machine.run_fast(&program).unwrap().to_vec();
+        let executed = machine.executed();
+
+        let watched = OutputBuffer::new();
+        let mut machine = MachineState::sandboxed()
+            .with_output(watched.clone())
+            .on_move(|_, _| {});
+        assert_eq!(machine.run(&program).unwrap(), expected.as_slice());
+        assert_eq!(machine.executed(), executed);
+        assert_eq!(watched.contents(), output.contents());
+    }
+
+    #[test]
+    fn injected_operations() {
+        let mut machine = MachineState::sandboxed();
+        machine
+            .run(&AST::from_string(String::from("++>")))
+            .expect("Execution failed");
+
+        let memory = machine
+            .execute_ops(&[
+                Operation::ChangeBy(Computation::Add, 5),
+                Operation::Move(Direction::Left),
+                Operation::SetZero,
+            ])
+            .expect("Execution failed");
+
+        assert_eq!(memory, [0, 5]);
+        assert_eq!(machine.executed(), 6);
+    }
+
+    #[test]
+    fn clean_run() {
+        let mut machine = MachineState::sandboxed();
+        let program = AST::from_string(String::from("++[>+<-]"));
+        assert_eq!(machine.run_expect_clean(&program).unwrap(), [0, 2]);
+
+        let mut machine = MachineState::sandboxed();
+        let program = AST::from_string(String::from("++[>+<-]>"));
+        let error = machine.run_expect_clean(&program).unwrap_err();
+        assert_eq!(
+            error.into_inner().unwrap().downcast_ref::<RunError>(),
+            Some(&RunError::UnbalancedPointer(1))
+        );
+    }
+
+    #[test]
+    fn output_limit() {
+        let output = OutputBuffer::new();
+        let mut machine = MachineState::sandboxed()
+            .with_output(output.clone())
+            .with_output_limit(5);
+
+        let error = machine
+            .run(&AST::from_string(String::from("+[.]")))
+            .unwrap_err();
+
+        assert_eq!(
+            error.into_inner().unwrap().downcast_ref::<RunError>(),
+            Some(&RunError::OutputLimitReached(5))
+        );
+        assert_eq!(output.contents(), [1; 5]);
+    }
+
+    #[test]
+    fn io_transcript() {
+        let mut machine = MachineState::new()
+            .with_input_bytes(b"ab")
+            .with_output(io::sink())
+            .with_transcript();
+
+        let ast = AST::from_string(String::from(",+.,+.,."));
+        machine.run(&ast).expect("Execution failed");
+
+        let transcript = machine.transcript().unwrap();
+        assert_eq!(
+            transcript.events,
+            vec![
+                IoEvent::Input(b'a'),
+                IoEvent::Output(b'b'),
+                IoEvent::Input(b'b'),
+                IoEvent::Output(b'c'),
+                IoEvent::Output(0),
+            ]
+        );
+        assert_eq!(transcript.inputs(), b"ab");
+        assert_eq!(transcript.outputs(), b"bc\0");
+        assert!(MachineState::sandboxed().transcript().is_none());
+    }
+
+    #[test]
+    fn error_line() {
+        let (program, map) = AST::from_with_lines("+.\n[\n  -.\n]\n");
+        let mut machine = MachineState::sandboxed()
+            .with_output_limit(1)
+            .with_source_map(map);
+
+        let error = machine.run(&program).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "error on line 3: output limit of 1 bytes reached"
+        );
+
+        let located = error.into_inner().unwrap().downcast::<LineError>().unwrap();
+        assert_eq!(located.line, 3);
+        assert_eq!(
+            located
+                .error
+                .into_inner()
+                .unwrap()
+                .downcast_ref::<RunError>(),
+            Some(&RunError::OutputLimitReached(1))
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn json_trace() {
+        let trace = OutputBuffer::new();
+        let mut machine = MachineState::sandboxed().with_json_trace(trace.clone());
+
+        let ast = AST::from_string(String::from("+>[-]<."));
+        machine.run(&ast).expect("Execution failed");
+
+        assert_eq!(
+            String::from_utf8(trace.contents()).unwrap(),
+            "{\"op\":\"+\",\"ptr\":0,\"cell\":1}\n\
+             {\"op\":\">\",\"ptr\":1,\"cell\":0}\n\
+             {\"op\":\"<\",\"ptr\":0,\"cell\":1}\n\
+             {\"op\":\".\",\"ptr\":0,\"cell\":1}\n"
+        );
+    }
+
+    #[test]
+    fn cancellation() {
+        use std::sync::atomic::AtomicBool;
+
+        let cancel = Arc::new(AtomicBool::new(false));
+        let stopper = Arc::clone(&cancel);
+        let handle = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            stopper.store(true, Ordering::Relaxed);
+        });
+
+        let mut machine = MachineState::sandboxed();
+        let error = machine
+            .run_cancellable(&AST::from_string(String::from("+[]")), &cancel)
+            .unwrap_err();
+        handle.join().unwrap();
+
+        assert_eq!(
+            error.into_inner().unwrap().downcast_ref::<RunError>(),
+            Some(&RunError::Cancelled)
+        );
+
+        cancel.store(false, Ordering::Relaxed);
+        let mut machine = MachineState::sandboxed();
+        let memory = machine
+            .run_cancellable(&AST::from_string(String::from("++[>+<-]")), &cancel)
+            .unwrap();
+        assert_eq!(memory, [0, 2]);
+
+        let error = MachineState::sandboxed()
+            .with_loop_iteration_limit(10)
+            .run_cancellable(&AST::from_string(String::from("+[]")), &cancel)
+            .unwrap_err();
+        assert!(matches!(
+            run_error(&error),
+            Some(RunError::LoopLimitExceeded(_))
+        ));
+    }
+
+    #[test]
+    fn labelled_dump() {
+        let mut machine = MachineState::sandboxed();
+        machine.label_region(0..1, "counter");
+        machine.label_region(1..3, "buffer");
+
+        machine
+            .run(&AST::from_string(String::from("+++>>-<")))
+            .expect("Execution failed");
+
+        assert_eq!(machine.to_string(), " counter: 03    buffer: 00 <  FF   ");
+    }
+
+    #[test]
+    fn checked_access() {
+        let mut machine = MachineState::sandboxed();
+        machine
+            .run(&AST::from_string(String::from(">+")))
+            .expect("Execution failed");
+
+        assert_eq!(machine.get(1), Some(1));
+        assert_eq!(machine.get(2), None);
+
+        *machine.get_mut(0).unwrap() = 7;
+        assert_eq!(machine[0], 7);
+        assert!(machine.get_mut(2).is_none());
+    }
+
+    #[test]
+    fn random_init() {
+        let run = |seed| {
+            let mut machine = MachineState::sandboxed().with_random_init(seed);
+            let ast = AST::from_string(String::from("+>>>>>>>[-]<"));
+            machine.run(&ast).expect("Execution failed").to_vec()
+        };
+
+        let memory = run(42);
+        assert_eq!(memory, run(42));
+        assert_ne!(memory, run(43));
+        assert_eq!((memory[0], memory[7]), (1, 0));
+        assert!(memory[1..7].iter().any(|cell| *cell != 0));
     }
 
     #[test]
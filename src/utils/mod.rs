@@ -1,2 +1,18 @@
+pub mod analysis;
 pub mod ast;
+pub mod codegen;
+pub mod debugger;
+pub mod dialect;
+pub mod frames;
+mod input;
 pub mod machine;
+pub mod optimizer;
+pub mod output;
+pub mod program;
+pub mod snapshot;
+pub mod stepper;
+mod streaming;
+pub mod tape;
+pub mod token;
+pub mod transcript;
+pub mod visitor;
@@ -0,0 +1,864 @@
+use super::ast::AST;
+use super::machine::{Computation, Direction, Operation};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::hash::{Hash, Hasher};
+
+/// Number of rewrites applied by each optimization.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct OptReport {
+    pub clear_loops: usize,
+    pub scan_loops: usize,
+    pub cancelled_pairs: usize,
+    pub coalesced_runs: usize,
+    pub dead_loops: usize,
+    pub set_cells: usize,
+}
+
+/// A rewrite of programs that keeps their behaviour.
+pub trait OptPass {
+    fn apply(&self, ast: AST) -> AST;
+}
+
+/// Removes `+-` and `-+` pairs.
+pub struct CancelPairs;
+
+/// Replaces `[-]` and `[+]` with [`Operation::SetZero`].
+pub struct ClearLoops;
+
+/// Replaces `[>]` and `[<]` with [`Operation::Scan`].
+pub struct ScanLoops;
+
+/// Replaces runs of moves or changes with [`Operation::MoveBy`] and [`Operation::ChangeBy`].
+pub struct CoalesceRuns;
+
+/// Replaces `[-]` followed by changes with [`Operation::SetCell`].
+pub struct SetCells;
+
+/// Removes loops entered on a cell known to be zero, since a loop, a clear or a scan just ended
+/// on it. Loops starting the program are kept, as the tape may not start zeroed, unless the pass
+/// is built with [`assuming_zeroed_start`](DeadLoops::assuming_zeroed_start).
+#[derive(Default)]
+pub struct DeadLoops {
+    zeroed_start: bool,
+}
+
+impl DeadLoops {
+    pub fn new() -> DeadLoops {
+        DeadLoops::default()
+    }
+
+    /// Also removes the loops starting the program, like leading comment loops, for programs
+    /// run on a zeroed tape.
+    pub fn assuming_zeroed_start() -> DeadLoops {
+        DeadLoops { zeroed_start: true }
+    }
+}
+
+/// Replaces straight-line moves and changes with an [`Operation::Block`] applying them at once.
+/// Not part of the default pipeline. Code moving left of where it started is left as is, since
+/// moves stop on the first cell.
+pub struct FoldBlocks;
+
+type Rewrite = fn(Vec<Node>, &mut OptReport) -> Vec<Node>;
+
+const DEFAULT_PIPELINE: [Rewrite; 6] = [
+    cancel_pairs,
+    fold_clear_loops,
+    fold_scan_loops,
+    coalesce_runs,
+    fold_set_cells,
+    drop_dead_loops,
+];
+
+fn rewrite(ast: AST, pass: Rewrite) -> AST {
+    from_nodes(pass(into_nodes(ast), &mut OptReport::default()))
+}
+
+impl OptPass for CancelPairs {
+    fn apply(&self, ast: AST) -> AST {
+        rewrite(ast, cancel_pairs)
+    }
+}
+
+impl OptPass for ClearLoops {
+    fn apply(&self, ast: AST) -> AST {
+        rewrite(ast, fold_clear_loops)
+    }
+}
+
+impl OptPass for ScanLoops {
+    fn apply(&self, ast: AST) -> AST {
+        rewrite(ast, fold_scan_loops)
+    }
+}
+
+impl OptPass for CoalesceRuns {
+    fn apply(&self, ast: AST) -> AST {
+        rewrite(ast, coalesce_runs)
+    }
+}
+
+impl OptPass for SetCells {
+    fn apply(&self, ast: AST) -> AST {
+        rewrite(ast, fold_set_cells)
+    }
+}
+
+impl OptPass for DeadLoops {
+    fn apply(&self, ast: AST) -> AST {
+        if self.zeroed_start {
+            rewrite(ast, drop_dead_loops_from_start)
+        } else {
+            rewrite(ast, drop_dead_loops)
+        }
+    }
+}
+
+impl OptPass for FoldBlocks {
+    fn apply(&self, ast: AST) -> AST {
+        from_nodes(fold_blocks(into_nodes(ast)))
+    }
+}
+
+/// Runs an ordered list of passes until none of them changes the program anymore.
+pub struct Optimizer {
+    passes: Vec<Box<dyn OptPass>>,
+}
+
+impl Default for Optimizer {
+    fn default() -> Optimizer {
+        Optimizer::new()
+            .with_pass(CancelPairs)
+            .with_pass(ClearLoops)
+            .with_pass(ScanLoops)
+            .with_pass(CoalesceRuns)
+            .with_pass(SetCells)
+            .with_pass(DeadLoops::new())
+    }
+}
+
+impl Optimizer {
+    /// An optimizer without any pass.
+    pub fn new() -> Optimizer {
+        Optimizer { passes: Vec::new() }
+    }
+
+    pub fn with_pass<P: OptPass + 'static>(mut self, pass: P) -> Optimizer {
+        self.passes.push(Box::new(pass));
+        self
+    }
+
+    pub fn optimize(&self, mut ast: AST) -> AST {
+        if self.passes.is_empty() {
+            return ast;
+        }
+
+        loop {
+            let before = ast.clone();
+            for pass in &self.passes {
+                ast = pass.apply(ast);
+            }
+            if ast == before {
+                return ast;
+            }
+        }
+    }
+}
+
+enum Node {
+    Operation(Operation),
+    Loop(Vec<Node>),
+}
+
+fn into_nodes(mut ast: AST) -> Vec<Node> {
+    let mut nodes = Vec::new();
+    loop {
+        match ast {
+            AST::Instructions(operations, next) => {
+                nodes.extend(operations.into_iter().map(Node::Operation));
+                ast = *next;
+            }
+            AST::Loop(body, next) => {
+                nodes.push(Node::Loop(into_nodes(*body)));
+                ast = *next;
+            }
+            AST::EOF => return nodes,
+        }
+    }
+}
+
+fn from_nodes(nodes: Vec<Node>) -> AST {
+    let mut ast = AST::EOF;
+    let mut operations = Vec::new();
+    for node in nodes.into_iter().rev() {
+        match node {
+            Node::Operation(operation) => operations.push(operation),
+            Node::Loop(body) => {
+                ast = AST::Loop(
+                    Box::from(from_nodes(body)),
+                    Box::from(instructions(&mut operations, ast)),
+                );
+            }
+        }
+    }
+    instructions(&mut operations, ast)
+}
+
+/// Prepends the operations collected in reverse order to `next`.
+fn instructions(reversed: &mut Vec<Operation>, next: AST) -> AST {
+    if reversed.is_empty() {
+        next
+    } else {
+        let mut operations = std::mem::take(reversed);
+        operations.reverse();
+        AST::Instructions(operations, Box::from(next))
+    }
+}
+
+fn map_loops<F>(nodes: Vec<Node>, pass: &mut F) -> Vec<Node>
+where
+    F: FnMut(Vec<Node>) -> Vec<Node>,
+{
+    nodes
+        .into_iter()
+        .map(|node| match node {
+            Node::Loop(body) => Node::Loop(pass(body)),
+            operation => operation,
+        })
+        .collect()
+}
+
+/// Removes `+-` and `-+`. Moves are kept, since they stop at the ends of the tape: `<>` on the
+/// first cell and `><` on the last cell of a fixed size tape both move the pointer.
+fn cancel_pairs(nodes: Vec<Node>, report: &mut OptReport) -> Vec<Node> {
+    let mut kept: Vec<Node> = Vec::new();
+    for node in map_loops(nodes, &mut |body| cancel_pairs(body, report)) {
+        let cancels = match (kept.last(), &node) {
+            (Some(Node::Operation(previous)), Node::Operation(operation)) => matches!(
+                (previous, operation),
+                (
+                    Operation::Change(Computation::Add),
+                    Operation::Change(Computation::Substract)
+                ) | (
+                    Operation::Change(Computation::Substract),
+                    Operation::Change(Computation::Add)
+                )
+            ),
+            _ => false,
+        };
+
+        if cancels {
+            kept.pop();
+            report.cancelled_pairs += 1;
+        } else {
+            kept.push(node);
+        }
+    }
+    kept
+}
+
+/// Replaces `[-]` and `[+]` with a single assignment.
+fn fold_clear_loops(nodes: Vec<Node>, report: &mut OptReport) -> Vec<Node> {
+    map_loops(nodes, &mut |body| fold_clear_loops(body, report))
+        .into_iter()
+        .map(|node| match node {
+            Node::Loop(body) => match body.as_slice() {
+                [Node::Operation(Operation::Change(_))] => {
+                    report.clear_loops += 1;
+                    Node::Operation(Operation::SetZero)
+                }
+                _ => Node::Loop(body),
+            },
+            operation => operation,
+        })
+        .collect()
+}
+
+/// Replaces `[>]` and `[<]` with a single scan.
+fn fold_scan_loops(nodes: Vec<Node>, report: &mut OptReport) -> Vec<Node> {
+    map_loops(nodes, &mut |body| fold_scan_loops(body, report))
+        .into_iter()
+        .map(|node| match node {
+            Node::Loop(body) => match body.as_slice() {
+                [Node::Operation(Operation::Move(direction))] => {
+                    report.scan_loops += 1;
+                    Node::Operation(Operation::Scan(direction.clone()))
+                }
+                _ => Node::Loop(body),
+            },
+            operation => operation,
+        })
+        .collect()
+}
+
+/// Folds changes following a clear or an assignment into the assigned value.
+fn fold_set_cells(nodes: Vec<Node>, report: &mut OptReport) -> Vec<Node> {
+    let mut folded: Vec<Node> = Vec::new();
+    for node in map_loops(nodes, &mut |body| fold_set_cells(body, report)) {
+        let value = match folded.last() {
+            Some(Node::Operation(Operation::SetZero)) => 0,
+            Some(Node::Operation(Operation::SetCell(value))) => *value,
+            _ => {
+                folded.push(node);
+                continue;
+            }
+        };
+        let value = match &node {
+            Node::Operation(Operation::Change(Computation::Add)) => value.wrapping_add(1),
+            Node::Operation(Operation::Change(Computation::Substract)) => value.wrapping_sub(1),
+            Node::Operation(Operation::ChangeBy(Computation::Add, amount)) => {
+                value.wrapping_add(*amount)
+            }
+            Node::Operation(Operation::ChangeBy(Computation::Substract, amount)) => {
+                value.wrapping_sub(*amount)
+            }
+            _ => {
+                folded.push(node);
+                continue;
+            }
+        };
+
+        report.set_cells += 1;
+        folded.pop();
+        folded.push(Node::Operation(if value == 0 {
+            Operation::SetZero
+        } else {
+            Operation::SetCell(value)
+        }));
+    }
+    folded
+}
+
+/// Removes the loops that can never run, clears and scans included: at the start of the
+/// program, and after a loop, a clear or a scan, all of which leave the pointer on a zero cell.
+fn drop_dead_loops(nodes: Vec<Node>, report: &mut OptReport) -> Vec<Node> {
+    drop_loops_after(nodes, false, report)
+}
+
+fn drop_dead_loops_from_start(nodes: Vec<Node>, report: &mut OptReport) -> Vec<Node> {
+    drop_loops_after(nodes, true, report)
+}
+
+fn drop_loops_after(nodes: Vec<Node>, mut zero: bool, report: &mut OptReport) -> Vec<Node> {
+    let mut kept = Vec::new();
+    for node in nodes {
+        match node {
+            Node::Loop(_) | Node::Operation(Operation::SetZero | Operation::Scan(_)) if zero => {
+                report.dead_loops += 1
+            }
+            Node::Loop(body) => {
+                kept.push(Node::Loop(drop_loops_after(body, false, report)));
+                zero = true;
+            }
+            Node::Operation(operation) => {
+                zero = match operation {
+                    Operation::SetZero | Operation::Scan(_) => true,
+                    Operation::Print | Operation::Debug => zero,
+                    _ => false,
+                };
+                kept.push(Node::Operation(operation));
+            }
+        }
+    }
+    kept
+}
+
+fn strip_debug(nodes: Vec<Node>) -> Vec<Node> {
+    map_loops(nodes, &mut strip_debug)
+        .into_iter()
+        .filter(|node| !matches!(node, Node::Operation(Operation::Debug)))
+        .collect()
+}
+
+/// Moves and changes being folded into a block, with offsets relative to the first cell.
+#[derive(Default)]
+struct PendingBlock {
+    operations: Vec<Operation>,
+    offset: isize,
+    furthest: isize,
+    deltas: BTreeMap<isize, i16>,
+    moves: bool,
+    changes: bool,
+}
+
+impl PendingBlock {
+    /// Adds `operation` to the block, unless it is neither a move nor a change, or it moves
+    /// left of the first cell.
+    fn push(&mut self, operation: &Operation) -> bool {
+        let (changes, end) = match operation {
+            Operation::Move(Direction::Right) => (vec![], 1),
+            Operation::Move(Direction::Left) => (vec![], -1),
+            Operation::MoveBy(Direction::Right, count) => (vec![], *count as isize),
+            Operation::MoveBy(Direction::Left, count) => (vec![], -(*count as isize)),
+            Operation::Change(Computation::Add) => (vec![(0, 1)], 0),
+            Operation::Change(Computation::Substract) => (vec![(0, -1)], 0),
+            Operation::ChangeBy(Computation::Add, amount) => (vec![(0, i16::from(*amount))], 0),
+            Operation::ChangeBy(Computation::Substract, amount) => {
+                (vec![(0, -i16::from(*amount))], 0)
+            }
+            Operation::Block { deltas, net_move } => (deltas.clone(), *net_move),
+            _ => return false,
+        };
+        let offsets = changes.iter().map(|(offset, _)| *offset);
+        if offsets
+            .chain(Some(end))
+            .any(|offset| self.offset + offset < 0)
+        {
+            return false;
+        }
+
+        for (offset, delta) in changes {
+            let cell = self.offset + offset;
+            self.furthest = self.furthest.max(cell);
+            let total = self.deltas.entry(cell).or_insert(0);
+            *total = (*total + delta) % 256;
+            self.changes |= delta != 0;
+        }
+        self.offset += end;
+        self.furthest = self.furthest.max(self.offset);
+        self.moves |= end != 0 || matches!(operation, Operation::Block { .. });
+        self.operations.push(operation.clone());
+        true
+    }
+
+    /// The block, or its operations when folding them wouldn't help.
+    fn finish(self) -> Vec<Node> {
+        if self.operations.len() < 2 || !self.moves || !self.changes {
+            return self.operations.into_iter().map(Node::Operation).collect();
+        }
+
+        let mut deltas: Vec<(isize, i16)> = self
+            .deltas
+            .into_iter()
+            .filter(|(_, delta)| *delta != 0)
+            .collect();
+        // Keeps the tape growing as far as the original operations did.
+        let visited = deltas
+            .last()
+            .map_or(0, |(offset, _)| *offset)
+            .max(self.offset);
+        if self.furthest > visited {
+            deltas.push((self.furthest, 0));
+        }
+        vec![Node::Operation(Operation::Block {
+            deltas,
+            net_move: self.offset,
+        })]
+    }
+}
+
+/// Folds runs of moves and changes into blocks.
+fn fold_blocks(nodes: Vec<Node>) -> Vec<Node> {
+    let mut folded = Vec::new();
+    let mut block = PendingBlock::default();
+    for node in map_loops(nodes, &mut fold_blocks) {
+        if let Node::Operation(operation) = &node {
+            if block.push(operation) {
+                continue;
+            }
+        }
+
+        folded.extend(std::mem::take(&mut block).finish());
+        match node {
+            Node::Operation(operation) if block.push(&operation) => {}
+            node => folded.push(node),
+        }
+    }
+    folded.extend(block.finish());
+    folded
+}
+
+fn fused(operation: &Operation, count: usize) -> Option<Operation> {
+    match operation {
+        Operation::Move(direction) => Some(Operation::MoveBy(direction.clone(), count)),
+        Operation::Change(computation) => {
+            let amount = (count % 256) as u8;
+            if amount == 0 {
+                None
+            } else {
+                Some(Operation::ChangeBy(computation.clone(), amount))
+            }
+        }
+        _ => unreachable!(),
+    }
+}
+
+fn flush_run(run: &mut Option<(Operation, usize)>, nodes: &mut Vec<Node>, report: &mut OptReport) {
+    match run.take() {
+        Some((operation, 1)) => nodes.push(Node::Operation(operation)),
+        Some((operation, count)) => {
+            report.coalesced_runs += 1;
+            nodes.extend(fused(&operation, count).map(Node::Operation));
+        }
+        None => {}
+    }
+}
+
+/// Replaces runs of the same move or change with a single fused operation.
+fn coalesce_runs(nodes: Vec<Node>, report: &mut OptReport) -> Vec<Node> {
+    let mut coalesced: Vec<Node> = Vec::new();
+    let mut run: Option<(Operation, usize)> = None;
+
+    for node in nodes {
+        match node {
+            Node::Operation(operation @ Operation::Move(_))
+            | Node::Operation(operation @ Operation::Change(_)) => match run.as_mut() {
+                Some((current, count)) if *current == operation => *count += 1,
+                _ => {
+                    flush_run(&mut run, &mut coalesced, report);
+                    run = Some((operation, 1));
+                }
+            },
+            Node::Loop(body) => {
+                flush_run(&mut run, &mut coalesced, report);
+                coalesced.push(Node::Loop(coalesce_runs(body, report)));
+            }
+            operation => {
+                flush_run(&mut run, &mut coalesced, report);
+                coalesced.push(operation);
+            }
+        }
+    }
+    flush_run(&mut run, &mut coalesced, report);
+
+    coalesced
+}
+
+impl AST {
+    /// Runs the default [`Optimizer`].
+    pub fn optimized(self) -> AST {
+        Optimizer::default().optimize(self)
+    }
+
+    /// The program without its `Debug` operations. Loops emptied this way are kept, since they
+    /// still loop forever on a nonzero cell.
+    pub fn strip_debug(self) -> AST {
+        from_nodes(strip_debug(into_nodes(self)))
+    }
+
+    /// Hash of the optimized program, equal for programs optimizing to the same one. Hashes are
+    /// only stable for a given build of the crate.
+    pub fn canonical_hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.clone().optimized().hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Same as [`optimized`](AST::optimized), also counting the rewrites.
+    pub fn optimize_with_report(self) -> (AST, OptReport) {
+        let mut report = OptReport::default();
+        let mut nodes = into_nodes(self);
+
+        loop {
+            let before = report.clone();
+            for pass in DEFAULT_PIPELINE.iter() {
+                nodes = pass(nodes, &mut report);
+            }
+            if report == before {
+                return (from_nodes(nodes), report);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::machine::MachineState;
+    use super::super::program::OptLevel;
+    use super::*;
+
+    #[test]
+    fn report_counts() {
+        let program = AST::from_string(String::from("+-+++[-]>><<<>[>+-]"));
+
+        let (optimized, report) = program.optimize_with_report();
+
+        assert_eq!(
+            report,
+            OptReport {
+                clear_loops: 1,
+                scan_loops: 1,
+                cancelled_pairs: 2,
+                coalesced_runs: 3,
+                dead_loops: 0,
+                set_cells: 0,
+            }
+        );
+        assert_eq!(
+            optimized,
+            AST::Instructions(
+                vec![
+                    Operation::ChangeBy(Computation::Add, 3),
+                    Operation::SetZero,
+                    Operation::MoveBy(Direction::Right, 2),
+                    Operation::MoveBy(Direction::Left, 3),
+                    Operation::Move(Direction::Right),
+                    Operation::Scan(Direction::Right),
+                ],
+                Box::from(AST::EOF)
+            )
+        );
+        assert_eq!(optimized.to_string(), "+++[-]>><<<>[>]");
+    }
+
+    #[test]
+    fn clamped_moves() {
+        let program = AST::from_string(String::from(">>><+"));
+        let run = |program: &AST| {
+            let mut machine = MachineState::with_ring_tape(3).with_ring_clamping();
+            let tape = machine.run(program).unwrap().to_vec();
+            (tape, machine.pointer())
+        };
+
+        assert_eq!(run(&program), (vec![0, 1, 0], 1));
+        assert_eq!(run(&program.clone().optimized()), run(&program));
+        let mut machine = MachineState::with_ring_tape(3).with_ring_clamping();
+        let compiled = program.compile_with(OptLevel::Full);
+        assert_eq!(machine.run_program(&compiled).unwrap(), [0, 1, 0]);
+        assert_eq!(machine.pointer(), 1);
+    }
+
+    #[test]
+    fn same_behaviour() {
+        let source = std::fs::read_to_string("tests/hello_world.bf").unwrap();
+        let program = AST::from_string(source);
+
+        let mut machine = MachineState::sandboxed();
+        let expected: Vec<u8> = machine
+            .run_yielding(&program)
+            .collect::<std::io::Result<_>>()
+            .unwrap();
+
+        let optimized = program.optimized();
+        let mut machine = MachineState::sandboxed();
+        let output: Vec<u8> = machine
+            .run_yielding(&optimized)
+            .collect::<std::io::Result<_>>()
+            .unwrap();
+
+        assert_eq!(output, expected);
+    }
+
+    struct StripDebug;
+
+    impl OptPass for StripDebug {
+        fn apply(&self, ast: AST) -> AST {
+            AST::from_string(ast.to_string().replace('#', ""))
+        }
+    }
+
+    #[test]
+    fn custom_pipeline() {
+        let program = AST::from_string(String::from("+#-[-]#"));
+
+        assert_eq!(Optimizer::new().optimize(program.clone()), program);
+        assert_eq!(
+            Optimizer::new()
+                .with_pass(StripDebug)
+                .with_pass(CancelPairs)
+                .optimize(program.clone())
+                .to_string(),
+            "[-]"
+        );
+        assert_eq!(
+            Optimizer::new()
+                .with_pass(CancelPairs)
+                .with_pass(StripDebug)
+                .optimize(program)
+                .to_string(),
+            "[-]"
+        );
+    }
+
+    #[test]
+    fn stripped_debug() {
+        let program = AST::from_string(String::from("#+#>[#]-[<#.]##"));
+        let stripped = program.strip_debug();
+
+        assert_eq!(stripped, AST::from_string(String::from("+>[]-[<.]")));
+        assert_eq!(AST::from_string(String::from("##")).strip_debug(), AST::EOF);
+    }
+
+    #[test]
+    fn set_cells() {
+        let program = AST::from_string(String::from("+[[-]+++.[+]--->[-]-+-<]"));
+
+        let (optimized, report) = program.clone().optimize_with_report();
+        assert_eq!(report.set_cells, 3);
+        assert_eq!(
+            optimized,
+            AST::Instructions(
+                vec![Operation::Change(Computation::Add)],
+                Box::from(AST::Loop(
+                    Box::from(AST::Instructions(
+                        vec![
+                            Operation::SetCell(3),
+                            Operation::Print,
+                            Operation::SetCell(253),
+                            Operation::Move(Direction::Right),
+                            Operation::SetCell(255),
+                            Operation::Move(Direction::Left),
+                        ],
+                        Box::from(AST::EOF)
+                    )),
+                    Box::from(AST::EOF)
+                ))
+            )
+        );
+        assert_eq!(
+            optimized.to_string(),
+            format!("+[[-]+++.[-]{}>[-]{}<]", "+".repeat(253), "+".repeat(255))
+        );
+
+        // Changes wrapping back to zero leave a clear.
+        assert_eq!(
+            Optimizer::new()
+                .with_pass(ClearLoops)
+                .with_pass(SetCells)
+                .optimize(AST::from_string(String::from("[-]-+[+]+-")))
+                .to_string(),
+            "[-][-]"
+        );
+    }
+
+    #[test]
+    fn dead_loops() {
+        let program = AST::from_string(String::from(
+            "[comment [with] loops.][-]#[+.]>[-][<]+[>]. [<-][.]",
+        ));
+
+        let (optimized, report) = program.clone().optimize_with_report();
+        assert_eq!(report.dead_loops, 5);
+        assert_eq!(optimized.to_string(), "[[].]#>[-]+[>].");
+        assert_eq!(
+            Optimizer::new()
+                .with_pass(DeadLoops::assuming_zeroed_start())
+                .optimize(program)
+                .to_string(),
+            "#>[-]+[>]."
+        );
+        assert_eq!(
+            Optimizer::new()
+                .with_pass(DeadLoops::assuming_zeroed_start())
+                .optimize(AST::from_string(String::from("[>]+[[-][.]]")))
+                .to_string(),
+            "+[[-]]"
+        );
+
+        let transfer = AST::from_string(String::from("[->+<]"));
+        for program in [transfer.clone(), transfer.optimized()] {
+            let mut machine = MachineState::with_tape(vec![3, 0]);
+            assert_eq!(machine.run(&program).unwrap(), [0, 3]);
+        }
+    }
+
+    #[test]
+    fn folded_blocks() {
+        let fold = |source: &str| {
+            Optimizer::new()
+                .with_pass(FoldBlocks)
+                .optimize(AST::from_string(String::from(source)))
+        };
+
+        assert_eq!(
+            fold("++>--->+<<."),
+            AST::Instructions(
+                vec![
+                    Operation::Block {
+                        deltas: vec![(0, 2), (1, -3), (2, 1)],
+                        net_move: 0,
+                    },
+                    Operation::Print,
+                ],
+                Box::from(AST::EOF)
+            )
+        );
+        assert_eq!(fold("[->+<]").to_string(), "[->+<]");
+        assert_eq!(fold(">>><<<+").to_string(), "+>>><<<");
+
+        // Moving left of the first cell may be stopped by the start of the tape.
+        assert_eq!(
+            fold("<+>"),
+            AST::Instructions(
+                vec![
+                    Operation::Move(Direction::Left),
+                    Operation::Block {
+                        deltas: vec![(0, 1)],
+                        net_move: 1,
+                    },
+                ],
+                Box::from(AST::EOF)
+            )
+        );
+        assert_eq!(fold(">>>").to_string(), ">>>");
+
+        for source in ["+>>-<", ">+<<<-", "++>>>[-]"] {
+            let program = AST::from_string(String::from(source));
+            assert_eq!(fold(source).symbolic_effect(), program.symbolic_effect());
+            assert_eq!(fold(source).is_forward_only(), program.is_forward_only());
+            assert_eq!(
+                fold(source).min_tape_estimate(),
+                program.min_tape_estimate()
+            );
+        }
+    }
+
+    #[test]
+    fn blocks_behaviour() {
+        let run = |program: &AST| {
+            let mut machine = MachineState::sandboxed().with_input_bytes(b"rebf");
+            let output: Vec<u8> = machine
+                .run_yielding(program)
+                .collect::<std::io::Result<_>>()
+                .unwrap();
+            (output, machine.tape().to_vec(), machine.pointer())
+        };
+
+        let hello = std::fs::read_to_string("tests/hello_world.bf").unwrap();
+        for source in [
+            hello.as_str(),
+            ",[>+++<-]>.<<+>>>>>+<<-<",
+            "<<+>>+<-[->++>+<<]>.>.",
+            ",>,>,<[->+<]<+++[>>-<<-]>>.",
+        ] {
+            let program = AST::from_string(String::from(source));
+            for folded in [
+                Optimizer::new()
+                    .with_pass(FoldBlocks)
+                    .optimize(program.clone()),
+                Optimizer::default()
+                    .with_pass(FoldBlocks)
+                    .optimize(program.clone()),
+            ] {
+                assert_eq!(run(&folded), run(&program), "{:?}", source);
+            }
+        }
+    }
+
+    #[test]
+    fn canonical_hashes() {
+        let hash = |source: &str| AST::from_string(String::from(source)).canonical_hash();
+
+        assert_eq!(hash("+++[-]>"), hash("++ + [+] +- >"));
+        assert_eq!(hash("[>]"), hash("-+[>]"));
+        assert_ne!(hash("[>]"), hash("><[>]"));
+        assert_ne!(hash("+++[-]>"), hash("+++[-]<"));
+    }
+
+    #[test]
+    fn wrapping_runs() {
+        let program = AST::from_string("+".repeat(256) + ">" + &"-".repeat(257));
+
+        assert_eq!(
+            program.optimized(),
+            AST::Instructions(
+                vec![
+                    Operation::Move(Direction::Right),
+                    Operation::ChangeBy(Computation::Substract, 1)
+                ],
+                Box::from(AST::EOF)
+            )
+        );
+    }
+}
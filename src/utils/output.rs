@@ -0,0 +1,64 @@
+use super::ast::AST;
+use super::machine::{MachineState, OutputMode};
+use std::io::{self, prelude::*};
+use std::sync::{Arc, Mutex};
+
+/// In-memory output shared between its clones, so that the output of a machine can be read
+/// back after the run.
+#[derive(Clone, Debug, Default)]
+pub struct OutputBuffer {
+    bytes: Arc<Mutex<Vec<u8>>>,
+}
+
+impl OutputBuffer {
+    pub fn new() -> OutputBuffer {
+        OutputBuffer::default()
+    }
+
+    pub fn contents(&self) -> Vec<u8> {
+        self.bytes.lock().unwrap().clone()
+    }
+
+    /// Empties the buffer, returning what it held.
+    pub fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut *self.bytes.lock().unwrap())
+    }
+}
+
+impl Write for OutputBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.bytes.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AST {
+    /// Runs the program on `input` with a fresh machine, returning only the bytes it printed,
+    /// in [`OutputMode::Raw`].
+    pub fn run_for_output(&self, input: &[u8]) -> io::Result<Vec<u8>> {
+        let output = OutputBuffer::new();
+        MachineState::new()
+            .with_input_bytes(input)
+            .with_output(output.clone())
+            .with_output_mode(OutputMode::Raw)
+            .run(self)?;
+        Ok(output.take())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn output_of_run() {
+        let program = AST::from_string(String::from(",[+.,]>."));
+
+        assert_eq!(program.run_for_output(b"abc").unwrap(), b"bcd\0");
+        assert_eq!(program.run_for_output(b"").unwrap(), b"\0");
+    }
+}
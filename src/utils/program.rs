@@ -0,0 +1,441 @@
+use super::ast::AST;
+use super::machine::{Computation, Direction, MachineState, Operation};
+use super::optimizer::{CancelPairs, CoalesceRuns, Optimizer};
+use std::convert::TryFrom;
+use std::io;
+
+/// How much optimization happens before compiling, like `-O0` to `-O2`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum OptLevel {
+    /// One instruction per source operation.
+    None,
+    /// Cancelled pairs and coalesced runs.
+    Basic,
+    /// Everything the default [`Optimizer`] does.
+    Full,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Instruction {
+    Operation(Operation),
+    /// Jumps to the given instruction when the current cell is zero.
+    JumpIfZero(usize),
+    /// Jumps to the given instruction when the current cell is not zero.
+    JumpIfNotZero(usize),
+}
+
+/// Program compiled to a flat list of instructions.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Program {
+    instructions: Vec<Instruction>,
+}
+
+/// Header of encoded programs, followed by the format version.
+const MAGIC: &[u8] = b"RBF";
+const VERSION: u8 = 1;
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Appends `value` as a LEB128 varint.
+fn write_varint(bytes: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        bytes.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+fn read_varint(bytes: &mut impl Iterator<Item = u8>) -> io::Result<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let byte = bytes
+            .next()
+            .ok_or_else(|| invalid_data(String::from("truncated program")))?;
+        value |= u64::from(byte & 0x7F) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(invalid_data(String::from("varint too long")))
+}
+
+/// Maps signed values to unsigned ones, small magnitudes staying small.
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+fn unzigzag(value: u64) -> i64 {
+    (value >> 1) as i64 ^ -((value & 1) as i64)
+}
+
+/// Longest move of decoded programs, in cells. Far beyond the tapes of real programs, it keeps
+/// crafted files from overflowing the pointer or growing the tape by gigabytes.
+const MAX_MOVE: u64 = 1 << 24;
+
+fn read_move(bytes: &mut impl Iterator<Item = u8>) -> io::Result<usize> {
+    let count = read_varint(bytes)?;
+    if count > MAX_MOVE {
+        return Err(invalid_data(String::from("move out of range")));
+    }
+    Ok(count as usize)
+}
+
+fn read_offset(bytes: &mut impl Iterator<Item = u8>) -> io::Result<isize> {
+    let offset = unzigzag(read_varint(bytes)?);
+    if offset.unsigned_abs() > MAX_MOVE {
+        return Err(invalid_data(String::from("block offset out of range")));
+    }
+    Ok(offset as isize)
+}
+
+impl Instruction {
+    /// Opcode of the instruction and its operands.
+    fn encode(&self) -> (u8, Vec<u64>) {
+        let (opcode, operand) = match self {
+            Instruction::Operation(Operation::Block { deltas, net_move }) => {
+                let mut operands = vec![deltas.len() as u64];
+                for (offset, delta) in deltas {
+                    operands.push(zigzag(*offset as i64));
+                    operands.push(zigzag(i64::from(*delta)));
+                }
+                operands.push(zigzag(*net_move as i64));
+                return (22, operands);
+            }
+            instruction => instruction.encode_single(),
+        };
+        (opcode, operand.into_iter().collect())
+    }
+
+    /// Opcode of the instructions taking at most one operand, and that operand.
+    fn encode_single(&self) -> (u8, Option<u64>) {
+        match self {
+            Instruction::Operation(operation) => match operation {
+                Operation::Move(Direction::Right) => (0, None),
+                Operation::Move(Direction::Left) => (1, None),
+                Operation::Change(Computation::Add) => (2, None),
+                Operation::Change(Computation::Substract) => (3, None),
+                Operation::Print => (4, None),
+                Operation::Read => (5, None),
+                Operation::Debug => (6, None),
+                Operation::MoveBy(Direction::Right, count) => (7, Some(*count as u64)),
+                Operation::MoveBy(Direction::Left, count) => (8, Some(*count as u64)),
+                Operation::ChangeBy(Computation::Add, amount) => (9, Some(u64::from(*amount))),
+                Operation::ChangeBy(Computation::Substract, amount) => {
+                    (10, Some(u64::from(*amount)))
+                }
+                Operation::SetZero => (11, None),
+                Operation::Scan(Direction::Right) => (12, None),
+                Operation::Scan(Direction::Left) => (13, None),
+                Operation::Move(Direction::Up) => (16, None),
+                Operation::Move(Direction::Down) => (17, None),
+                Operation::MoveBy(Direction::Up, count) => (18, Some(*count as u64)),
+                Operation::MoveBy(Direction::Down, count) => (19, Some(*count as u64)),
+                Operation::Scan(Direction::Up) => (20, None),
+                Operation::Scan(Direction::Down) => (21, None),
+                Operation::SetCell(value) => (23, Some(u64::from(*value))),
+                Operation::Block { .. } => unreachable!(),
+            },
+            Instruction::JumpIfZero(target) => (14, Some(*target as u64)),
+            Instruction::JumpIfNotZero(target) => (15, Some(*target as u64)),
+        }
+    }
+
+    fn decode(opcode: u8, bytes: &mut impl Iterator<Item = u8>) -> io::Result<Instruction> {
+        let operation = match opcode {
+            0 => Operation::Move(Direction::Right),
+            1 => Operation::Move(Direction::Left),
+            2 => Operation::Change(Computation::Add),
+            3 => Operation::Change(Computation::Substract),
+            4 => Operation::Print,
+            5 => Operation::Read,
+            6 => Operation::Debug,
+            7 => Operation::MoveBy(Direction::Right, read_move(bytes)?),
+            8 => Operation::MoveBy(Direction::Left, read_move(bytes)?),
+            9 | 10 => {
+                let amount = u8::try_from(read_varint(bytes)?)
+                    .map_err(|_| invalid_data(String::from("change amount out of range")))?;
+                let computation = if opcode == 9 {
+                    Computation::Add
+                } else {
+                    Computation::Substract
+                };
+                Operation::ChangeBy(computation, amount)
+            }
+            11 => Operation::SetZero,
+            12 => Operation::Scan(Direction::Right),
+            13 => Operation::Scan(Direction::Left),
+            14 => return Ok(Instruction::JumpIfZero(read_varint(bytes)? as usize)),
+            15 => return Ok(Instruction::JumpIfNotZero(read_varint(bytes)? as usize)),
+            16 => Operation::Move(Direction::Up),
+            17 => Operation::Move(Direction::Down),
+            18 => Operation::MoveBy(Direction::Up, read_move(bytes)?),
+            19 => Operation::MoveBy(Direction::Down, read_move(bytes)?),
+            20 => Operation::Scan(Direction::Up),
+            21 => Operation::Scan(Direction::Down),
+            22 => {
+                let count = read_varint(bytes)?;
+                let mut deltas = Vec::new();
+                for _ in 0..count {
+                    let offset = read_offset(bytes)?;
+                    let delta = i16::try_from(unzigzag(read_varint(bytes)?))
+                        .map_err(|_| invalid_data(String::from("block delta out of range")))?;
+                    deltas.push((offset, delta));
+                }
+                let net_move = read_offset(bytes)?;
+                Operation::Block { deltas, net_move }
+            }
+            23 => Operation::SetCell(
+                u8::try_from(read_varint(bytes)?)
+                    .map_err(|_| invalid_data(String::from("cell value out of range")))?,
+            ),
+            _ => return Err(invalid_data(format!("unknown opcode {}", opcode))),
+        };
+        Ok(Instruction::Operation(operation))
+    }
+}
+
+impl Program {
+    pub fn instructions(&self) -> &[Instruction] {
+        &self.instructions
+    }
+
+    /// Encodes the program as `RBF` and a version byte, followed by the number of
+    /// instructions as a varint, then each instruction as an opcode byte and its varint
+    /// operands, if any. Signed operands of blocks are zigzag encoded.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(VERSION);
+        write_varint(&mut bytes, self.instructions.len() as u64);
+        for instruction in &self.instructions {
+            let (opcode, operands) = instruction.encode();
+            bytes.push(opcode);
+            for operand in operands {
+                write_varint(&mut bytes, operand);
+            }
+        }
+        bytes
+    }
+
+    /// Decodes a program encoded by [`to_bytes`](Program::to_bytes), checking that its jumps
+    /// stay within the program.
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Program> {
+        let body = bytes
+            .strip_prefix(MAGIC)
+            .ok_or_else(|| invalid_data(String::from("not a compiled program")))?;
+        let (&version, body) = body
+            .split_first()
+            .ok_or_else(|| invalid_data(String::from("truncated program")))?;
+        if version != VERSION {
+            return Err(invalid_data(format!(
+                "unsupported program version {}",
+                version
+            )));
+        }
+
+        let mut bytes = body.iter().copied();
+        let count = read_varint(&mut bytes)? as usize;
+        let mut instructions = Vec::new();
+        for _ in 0..count {
+            let opcode = bytes
+                .next()
+                .ok_or_else(|| invalid_data(String::from("truncated program")))?;
+            let instruction = Instruction::decode(opcode, &mut bytes)?;
+            if let Instruction::JumpIfZero(target) | Instruction::JumpIfNotZero(target) =
+                instruction
+            {
+                if target > count {
+                    return Err(invalid_data(format!("jump to {} out of program", target)));
+                }
+            }
+            instructions.push(instruction);
+        }
+        if bytes.next().is_some() {
+            return Err(invalid_data(String::from("trailing bytes after program")));
+        }
+
+        Ok(Program { instructions })
+    }
+}
+
+fn lower(mut ast: &AST, instructions: &mut Vec<Instruction>) {
+    loop {
+        match ast {
+            AST::Instructions(operations, next) => {
+                instructions.extend(operations.iter().cloned().map(Instruction::Operation));
+                ast = next;
+            }
+            AST::Loop(body, next) => {
+                let start = instructions.len();
+                instructions.push(Instruction::JumpIfZero(0));
+                lower(body, instructions);
+                let end = instructions.len();
+                instructions.push(Instruction::JumpIfNotZero(start + 1));
+                instructions[start] = Instruction::JumpIfZero(end + 1);
+                ast = next;
+            }
+            AST::EOF => return,
+        }
+    }
+}
+
+impl AST {
+    pub fn compile_with(self, level: OptLevel) -> Program {
+        let ast = match level {
+            OptLevel::None => self,
+            OptLevel::Basic => Optimizer::new()
+                .with_pass(CancelPairs)
+                .with_pass(CoalesceRuns)
+                .optimize(self),
+            OptLevel::Full => self.optimized(),
+        };
+
+        let mut instructions = Vec::new();
+        lower(&ast, &mut instructions);
+        Program { instructions }
+    }
+}
+
+impl MachineState {
+    pub fn run_program(&mut self, program: &Program) -> io::Result<&[u8]> {
+        let mut counter = 0;
+        while let Some(instruction) = program.instructions.get(counter) {
+            counter += 1;
+            match instruction {
+                Instruction::Operation(operation) => {
+                    self.apply(operation)?;
+                }
+                Instruction::JumpIfZero(target) => {
+                    if self.get_current() == 0 {
+                        counter = *target;
+                    }
+                }
+                Instruction::JumpIfNotZero(target) => {
+                    if self.get_current() != 0 {
+                        counter = *target;
+                    }
+                }
+            }
+        }
+
+        Ok(self.tape())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::optimizer::FoldBlocks;
+    use super::*;
+
+    #[test]
+    fn lowering() {
+        let program = AST::from_string(String::from("+[-[.]]")).compile_with(OptLevel::None);
+
+        assert_eq!(
+            program.instructions(),
+            [
+                Instruction::Operation(Operation::Change(Computation::Add)),
+                Instruction::JumpIfZero(7),
+                Instruction::Operation(Operation::Change(Computation::Substract)),
+                Instruction::JumpIfZero(6),
+                Instruction::Operation(Operation::Print),
+                Instruction::JumpIfNotZero(4),
+                Instruction::JumpIfNotZero(2),
+            ]
+        );
+    }
+
+    #[test]
+    fn levels() {
+        let source = "++-[-]>>>[<]";
+        let sizes: Vec<usize> = [OptLevel::None, OptLevel::Basic, OptLevel::Full]
+            .iter()
+            .map(|level| {
+                AST::from_string(String::from(source))
+                    .compile_with(*level)
+                    .instructions()
+                    .len()
+            })
+            .collect();
+
+        assert_eq!(sizes, vec![12, 8, 4]);
+    }
+
+    #[test]
+    fn binary_round_trip() {
+        let source = std::fs::read_to_string("tests/hello_world.bf").unwrap();
+        for level in [OptLevel::None, OptLevel::Full] {
+            let program = AST::from_string(source.clone()).compile_with(level);
+            let bytes = program.to_bytes();
+
+            assert_eq!(&bytes[..4], b"RBF\x01");
+            assert_eq!(Program::from_bytes(&bytes).unwrap(), program);
+        }
+
+        let program = AST::from_string(String::from(">>>>+[.-]")).compile_with(OptLevel::Basic);
+        assert_eq!(
+            program.to_bytes(),
+            [b'R', b'B', b'F', 1, 6, 7, 4, 2, 14, 6, 4, 3, 15, 3]
+        );
+
+        let program = Optimizer::new()
+            .with_pass(FoldBlocks)
+            .optimize(AST::from_string(String::from("+>->><.")))
+            .compile_with(OptLevel::None);
+        let bytes = program.to_bytes();
+        assert_eq!(
+            bytes,
+            [b'R', b'B', b'F', 1, 2, 22, 3, 0, 2, 2, 1, 6, 0, 4, 4]
+        );
+        assert_eq!(Program::from_bytes(&bytes).unwrap(), program);
+
+        let program = AST::from_string(String::from("+[[-]++.]")).compile_with(OptLevel::Full);
+        let bytes = program.to_bytes();
+        assert_eq!(bytes, [b'R', b'B', b'F', 1, 5, 2, 14, 5, 23, 2, 4, 15, 2]);
+        assert_eq!(Program::from_bytes(&bytes).unwrap(), program);
+    }
+
+    #[test]
+    fn invalid_binaries() {
+        let error = |bytes: &[u8]| Program::from_bytes(bytes).unwrap_err().to_string();
+
+        assert_eq!(error(b"+++"), "not a compiled program");
+        assert_eq!(error(b"RBF\x02\x00"), "unsupported program version 2");
+        assert_eq!(error(b"RBF\x01\x02\x00"), "truncated program");
+        assert_eq!(error(b"RBF\x01\x01\x20"), "unknown opcode 32");
+        assert_eq!(error(b"RBF\x01\x01\x0E\x05"), "jump to 5 out of program");
+        assert_eq!(
+            error(b"RBF\x01\x01\x09\x80\x02"),
+            "change amount out of range"
+        );
+        assert_eq!(
+            error(b"RBF\x01\x01\x07\xFF\xFF\xFF\xFF\xFF\xFF\xFF\xFF\xFF\x01"),
+            "move out of range"
+        );
+        assert_eq!(
+            error(b"RBF\x01\x01\x16\x01\xFE\xFF\xFF\xFF\xFF\xFF\xFF\xFF\x7F\x02\x00"),
+            "block offset out of range"
+        );
+        assert_eq!(
+            error(b"RBF\x01\x01\x16\x00\x81\x80\x80\x10"),
+            "block offset out of range"
+        );
+        assert_eq!(error(b"RBF\x01\x00\x00"), "trailing bytes after program");
+    }
+
+    #[test]
+    fn same_behaviour() {
+        let source = std::fs::read_to_string("tests/hello_world.bf").unwrap();
+        let expected = MachineState::sandboxed()
+            .run(&AST::from_string(source.clone()))
+            .unwrap()
+            .to_vec();
+
+        for level in [OptLevel::None, OptLevel::Basic, OptLevel::Full] {
+            let program = AST::from_string(source.clone()).compile_with(level);
+            let mut machine = MachineState::sandboxed();
+            assert_eq!(machine.run_program(&program).unwrap(), expected.as_slice());
+        }
+    }
+}
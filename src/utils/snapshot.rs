@@ -0,0 +1,91 @@
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::convert::TryInto;
+use std::io;
+
+/// State of a machine at some point of a run.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Snapshot {
+    pub pointer: usize,
+    pub tape: Vec<u8>,
+    /// Operations executed so far.
+    pub executed: u64,
+    /// Index of the last executed operation, counting the operations of the program in source
+    /// order, when known. This is where a resumed run picks up.
+    pub location: Option<usize>,
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+impl Snapshot {
+    /// Encodes the snapshot as little endian `u64` pointer, executed count, location (plus one,
+    /// zero meaning unknown) and tape length, followed by the tape itself.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let location = self.location.map_or(0, |location| location as u64 + 1);
+
+        let mut bytes = Vec::with_capacity(32 + self.tape.len());
+        bytes.extend_from_slice(&(self.pointer as u64).to_le_bytes());
+        bytes.extend_from_slice(&self.executed.to_le_bytes());
+        bytes.extend_from_slice(&location.to_le_bytes());
+        bytes.extend_from_slice(&(self.tape.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(&self.tape);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Snapshot> {
+        if bytes.len() < 32 {
+            return Err(invalid_data("truncated snapshot header"));
+        }
+
+        let field =
+            |index: usize| u64::from_le_bytes(bytes[index * 8..index * 8 + 8].try_into().unwrap());
+        let (pointer, executed, location, len) = (field(0), field(1), field(2), field(3));
+
+        let tape = &bytes[32..];
+        if tape.len() as u64 != len {
+            return Err(invalid_data("tape length mismatch"));
+        }
+        if pointer >= len {
+            return Err(invalid_data("pointer outside of the tape"));
+        }
+
+        Ok(Snapshot {
+            pointer: pointer as usize,
+            tape: tape.to_vec(),
+            executed,
+            location: location.checked_sub(1).map(|location| location as usize),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bytes_round_trip() {
+        let snapshot = Snapshot {
+            pointer: 2,
+            tape: vec![1, 0, 255],
+            executed: 1234,
+            location: Some(0),
+        };
+
+        assert_eq!(
+            Snapshot::from_bytes(&snapshot.to_bytes()).unwrap(),
+            snapshot
+        );
+
+        let unknown = Snapshot {
+            location: None,
+            ..snapshot.clone()
+        };
+        assert_eq!(Snapshot::from_bytes(&unknown.to_bytes()).unwrap(), unknown);
+
+        let bytes = snapshot.to_bytes();
+        assert!(Snapshot::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,481 @@
+use super::ast::AST;
+use super::machine::{MachineState, Operation};
+use std::io;
+
+/// State needed to revert a step.
+struct Undo<'a> {
+    current: &'a AST,
+    index: usize,
+    loops: Vec<&'a AST>,
+    pending: Option<&'a Operation>,
+    operation: &'a Operation,
+    pointer: usize,
+    /// Cells written by the operation, with their previous values and written flags.
+    cells: Vec<(usize, u8, bool)>,
+    consumed: Option<u8>,
+}
+
+/// Watched cell taking its watched value, see [`Stepper::add_watchpoint`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WatchpointHit {
+    pub cell: usize,
+    pub value: u8,
+}
+
+/// Resumable execution of a program, one operation at a time.
+pub struct Stepper<'a> {
+    machine: &'a mut MachineState,
+    current: &'a AST,
+    index: usize,
+    loops: Vec<&'a AST>,
+    pending: Option<&'a Operation>,
+    history: Option<Vec<Undo<'a>>>,
+    watchpoints: Vec<WatchpointHit>,
+}
+
+impl<'a> Stepper<'a> {
+    pub fn new(machine: &'a mut MachineState, program: &'a AST) -> Stepper<'a> {
+        Stepper {
+            machine,
+            current: program,
+            index: 0,
+            loops: Vec::new(),
+            pending: None,
+            history: None,
+            watchpoints: Vec::new(),
+        }
+    }
+
+    /// Resumes a run of `program` right after the operation at `location`, as recorded in a
+    /// [`Snapshot`](super::snapshot::Snapshot). Returns `None` when `program` has no such
+    /// operation.
+    pub fn resume(
+        machine: &'a mut MachineState,
+        program: &'a AST,
+        location: usize,
+    ) -> Option<Stepper<'a>> {
+        fn find<'a>(
+            mut ast: &'a AST,
+            location: &mut usize,
+            loops: &mut Vec<&'a AST>,
+        ) -> Option<(&'a AST, usize)> {
+            loop {
+                match ast {
+                    AST::Instructions(operations, next) => {
+                        if *location < operations.len() {
+                            return Some((ast, *location + 1));
+                        }
+                        *location -= operations.len();
+                        ast = next;
+                    }
+                    AST::Loop(body, next) => {
+                        loops.push(ast);
+                        if let Some(found) = find(body, location, loops) {
+                            return Some(found);
+                        }
+                        loops.pop();
+                        ast = next;
+                    }
+                    AST::EOF => return None,
+                }
+            }
+        }
+
+        let mut loops = Vec::new();
+        let (current, index) = find(program, &mut { location }, &mut loops)?;
+        Some(Stepper {
+            machine,
+            current,
+            index,
+            loops,
+            pending: None,
+            history: None,
+            watchpoints: Vec::new(),
+        })
+    }
+
+    /// Records every step so that it can be reverted with [`step_back`](Stepper::step_back).
+    pub fn with_history(mut self) -> Stepper<'a> {
+        self.history = Some(Vec::new());
+        self
+    }
+
+    /// Makes [`run_to_watchpoint`](Stepper::run_to_watchpoint) pause the first time an
+    /// operation sets `cell` to `value`.
+    pub fn add_watchpoint(&mut self, cell: usize, value: u8) {
+        self.watchpoints.push(WatchpointHit { cell, value });
+    }
+
+    /// Steps until a watched cell takes its watched value, returning the watchpoint, which is
+    /// then removed. Returns `None` once the program is over.
+    pub fn run_to_watchpoint(&mut self) -> io::Result<Option<WatchpointHit>> {
+        loop {
+            let cell = self.machine.pointer();
+            let operation = match self.step()? {
+                Some(operation) => operation,
+                None => return Ok(None),
+            };
+
+            let written: Vec<usize> = match operation {
+                Operation::Change(_)
+                | Operation::ChangeBy(..)
+                | Operation::SetZero
+                | Operation::SetCell(_)
+                | Operation::Read => vec![cell],
+                Operation::Block { deltas, .. } => deltas
+                    .iter()
+                    .filter(|(_, delta)| *delta != 0)
+                    .filter_map(|(offset, _)| cell.checked_add_signed(*offset))
+                    .collect(),
+                _ => continue,
+            };
+            for cell in written {
+                let value = match self.machine.tape().get(cell) {
+                    Some(value) => *value,
+                    None => continue,
+                };
+                let hit = WatchpointHit { cell, value };
+                if let Some(index) = self.watchpoints.iter().position(|watch| *watch == hit) {
+                    self.watchpoints.remove(index);
+                    return Ok(Some(hit));
+                }
+            }
+        }
+    }
+
+    pub fn machine(&self) -> &MachineState {
+        self.machine
+    }
+
+    pub fn machine_mut(&mut self) -> &mut MachineState {
+        self.machine
+    }
+
+    fn next_operation(&mut self) -> Option<&'a Operation> {
+        if let Some(operation) = self.pending.take() {
+            return Some(operation);
+        }
+
+        loop {
+            match self.current {
+                AST::Instructions(operations, next) => {
+                    if let Some(operation) = operations.get(self.index) {
+                        self.index += 1;
+                        return Some(operation);
+                    }
+                    self.current = next;
+                    self.index = 0;
+                }
+                AST::Loop(body, next) => {
+                    if self.machine.get_current() != 0 {
+                        self.loops.push(self.current);
+                        self.current = body;
+                    } else {
+                        self.current = next;
+                    }
+                }
+                AST::EOF => match self.loops.pop() {
+                    Some(loop_node) => self.current = loop_node,
+                    None => return None,
+                },
+            }
+        }
+    }
+
+    /// Whether every operation of the current loop body was fetched.
+    fn at_body_end(&self) -> bool {
+        fn exhausted(ast: &AST, index: usize) -> bool {
+            match ast {
+                AST::Instructions(operations, next) => {
+                    index >= operations.len() && exhausted(next, 0)
+                }
+                AST::Loop(..) => false,
+                AST::EOF => true,
+            }
+        }
+
+        self.pending.is_none() && exhausted(self.current, self.index)
+    }
+
+    /// Runs the rest of the body of the innermost loop, then checks its condition to either
+    /// enter the next iteration or leave the loop, and pauses. Returns `false` without doing
+    /// anything outside of loops, or when the program ends first.
+    pub fn step_loop_iteration(&mut self) -> io::Result<bool> {
+        let depth = self.loops.len();
+        if depth == 0 {
+            return Ok(false);
+        }
+
+        while !(self.loops.len() == depth && self.at_body_end()) {
+            if self.step()?.is_none() {
+                return Ok(false);
+            }
+        }
+
+        if let Some(loop_node @ AST::Loop(body, next)) = self.loops.pop() {
+            self.index = 0;
+            if self.machine.get_current() != 0 {
+                self.loops.push(loop_node);
+                self.current = body;
+            } else {
+                self.current = next;
+            }
+        }
+        Ok(true)
+    }
+
+    /// Returns the operation the next step will execute, without executing it.
+    pub fn peek(&mut self) -> Option<&'a Operation> {
+        if self.pending.is_none() {
+            self.pending = self.next_operation();
+        }
+        self.pending
+    }
+
+    /// Moves past the next operation without executing it.
+    pub fn skip(&mut self) -> Option<&'a Operation> {
+        self.next_operation()
+    }
+
+    /// Executes the next operation and returns it, or `None` once the program is over.
+    pub fn step(&mut self) -> io::Result<Option<&'a Operation>> {
+        if self.history.is_none() {
+            return match self.next_operation() {
+                Some(operation) => {
+                    self.machine.apply(operation)?;
+                    Ok(Some(operation))
+                }
+                None => Ok(None),
+            };
+        }
+
+        let (current, index, loops, pending) =
+            (self.current, self.index, self.loops.clone(), self.pending);
+        let operation = match self.next_operation() {
+            Some(operation) => operation,
+            None => return Ok(None),
+        };
+        let (pointer, cell) = (self.machine.pointer(), self.machine.get_current());
+        let written = self.machine.is_written(pointer);
+        self.machine.apply(operation)?;
+
+        let consumed = match operation {
+            Operation::Read => self.machine.last_read(),
+            _ => None,
+        };
+        let cells = match operation {
+            Operation::Block { .. } => self.machine.block_writes().to_vec(),
+            _ => vec![(pointer, cell, written)],
+        };
+        if let Some(history) = self.history.as_mut() {
+            history.push(Undo {
+                current,
+                index,
+                loops,
+                pending,
+                operation,
+                pointer,
+                cells,
+                consumed,
+            });
+        }
+        Ok(Some(operation))
+    }
+
+    /// Reverts the last recorded step and returns its operation, or `None` when there is
+    /// nothing to revert. Printed output stays written, but bytes consumed by `Read` are read
+    /// again.
+    pub fn step_back(&mut self) -> Option<&'a Operation> {
+        let undo = self.history.as_mut()?.pop()?;
+
+        self.machine.undo(undo.pointer, &undo.cells, undo.consumed);
+        self.current = undo.current;
+        self.index = undo.index;
+        self.loops = undo.loops;
+        self.pending = undo.pending;
+        Some(undo.operation)
+    }
+}
+
+/// Output of a program, produced lazily as the program runs.
+pub struct OutputBytes<'a> {
+    stepper: Stepper<'a>,
+    done: bool,
+}
+
+impl<'a> OutputBytes<'a> {
+    pub fn new(stepper: Stepper<'a>) -> OutputBytes<'a> {
+        OutputBytes {
+            stepper,
+            done: false,
+        }
+    }
+}
+
+impl<'a> Iterator for OutputBytes<'a> {
+    type Item = io::Result<u8>;
+
+    fn next(&mut self) -> Option<io::Result<u8>> {
+        while !self.done {
+            match self.stepper.next_operation() {
+                Some(Operation::Print) => return Some(Ok(self.stepper.machine.get_current())),
+                Some(operation) => {
+                    if let Err(error) = self.stepper.machine.apply(operation) {
+                        self.done = true;
+                        return Some(Err(error));
+                    }
+                }
+                None => self.done = true,
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::machine::{Computation, Direction};
+    use super::super::optimizer::{FoldBlocks, Optimizer};
+    use super::super::output::OutputBuffer;
+    use super::*;
+
+    #[test]
+    fn step_through_loop() {
+        let mut machine = MachineState::sandboxed();
+        let program = AST::from_string(String::from("++[-]"));
+        let mut stepper = Stepper::new(&mut machine, &program);
+
+        let mut steps = 0;
+        while stepper.step().expect("Execution failed").is_some() {
+            steps += 1;
+        }
+
+        assert_eq!(steps, 4);
+        assert_eq!(stepper.machine()[0], 0);
+    }
+
+    #[test]
+    fn step_back() {
+        let mut machine = MachineState::sandboxed().with_input_bytes(&[5, 6]);
+        let program = AST::from_string(String::from(",[->+<],."));
+        let mut stepper = Stepper::new(&mut machine, &program).with_history();
+
+        while stepper.step().expect("Execution failed").is_some() {}
+        assert_eq!(stepper.machine().tape(), [6, 5]);
+        let executed = stepper.machine().executed();
+
+        assert_eq!(stepper.step_back(), Some(&Operation::Print));
+        assert_eq!(stepper.step_back(), Some(&Operation::Read));
+        assert_eq!(stepper.machine().tape(), [0, 5]);
+        assert_eq!(stepper.step_back(), Some(&Operation::Move(Direction::Left)));
+        assert_eq!(stepper.machine().pointer(), 1);
+
+        while stepper.step().expect("Execution failed").is_some() {}
+        assert_eq!(stepper.machine().tape(), [6, 5]);
+        assert_eq!(stepper.machine().executed(), executed);
+
+        while stepper.step_back().is_some() {}
+        assert_eq!(stepper.machine().tape(), [0, 0]);
+        assert_eq!(stepper.machine().executed(), 0);
+        while stepper.step().expect("Execution failed").is_some() {}
+        assert_eq!(stepper.machine().tape(), [6, 5]);
+    }
+
+    #[test]
+    fn step_back_blocks() {
+        let program = Optimizer::new()
+            .with_pass(FoldBlocks)
+            .optimize(AST::from_string(String::from("+>++[->+++>+<<]>->+<<")));
+        let mut machine = MachineState::sandboxed();
+        let mut stepper = Stepper::new(&mut machine, &program).with_history();
+
+        while stepper.step().expect("Execution failed").is_some() {}
+        assert_eq!(stepper.machine().tape(), [1, 0, 5, 3]);
+
+        assert!(matches!(stepper.step_back(), Some(Operation::Block { .. })));
+        assert_eq!(stepper.machine().tape(), [1, 0, 6, 2]);
+        assert_eq!(stepper.machine().pointer(), 1);
+        while stepper.step_back().is_some() {}
+        assert_eq!(stepper.machine().tape(), [0, 0, 0, 0]);
+        assert_eq!(stepper.machine().pointer(), 0);
+    }
+
+    #[test]
+    fn step_back_written() {
+        let program = AST::from_string(String::from("+>+"));
+        let folded = Optimizer::new()
+            .with_pass(FoldBlocks)
+            .optimize(program.clone());
+        for program in [program, folded] {
+            let mut machine = MachineState::sandboxed().with_write_tracking();
+            let mut stepper = Stepper::new(&mut machine, &program).with_history();
+
+            while stepper.step().expect("Execution failed").is_some() {}
+            assert_eq!(stepper.machine().written_cells(), 2);
+            while stepper.step_back().is_some() {}
+            assert_eq!(stepper.machine().written_cells(), 0);
+        }
+    }
+
+    #[test]
+    fn loop_iterations() {
+        let output = OutputBuffer::new();
+        let mut machine = MachineState::sandboxed().with_output(output.clone());
+        let program = AST::from_string(String::from("+++[-.]+"));
+        let mut stepper = Stepper::new(&mut machine, &program);
+
+        assert!(!stepper.step_loop_iteration().expect("Execution failed"));
+        for _ in 0..4 {
+            stepper.step().expect("Execution failed");
+        }
+
+        assert!(stepper.step_loop_iteration().expect("Execution failed"));
+        assert_eq!(output.contents(), [2]);
+        assert_eq!(
+            stepper.peek(),
+            Some(&Operation::Change(Computation::Substract))
+        );
+
+        assert!(stepper.step_loop_iteration().expect("Execution failed"));
+        assert!(stepper.step_loop_iteration().expect("Execution failed"));
+        assert_eq!(output.contents(), [2, 1, 0]);
+        assert_eq!(stepper.peek(), Some(&Operation::Change(Computation::Add)));
+        assert!(!stepper.step_loop_iteration().expect("Execution failed"));
+    }
+
+    #[test]
+    fn watchpoints() {
+        let mut machine = MachineState::sandboxed().with_input_bytes(b"A");
+        let program = AST::from_string(String::from("+++[>++<-]>,[-]"));
+        let mut stepper = Stepper::new(&mut machine, &program);
+        stepper.add_watchpoint(1, 4);
+        stepper.add_watchpoint(1, 65);
+        stepper.add_watchpoint(1, 4);
+        stepper.add_watchpoint(0, 7);
+
+        let hit = stepper.run_to_watchpoint().expect("Execution failed");
+        assert_eq!(hit, Some(WatchpointHit { cell: 1, value: 4 }));
+        assert_eq!(stepper.machine().tape(), [2, 4]);
+
+        let hit = stepper.run_to_watchpoint().expect("Execution failed");
+        assert_eq!(hit, Some(WatchpointHit { cell: 1, value: 65 }));
+        let hit = stepper.run_to_watchpoint().expect("Execution failed");
+        assert_eq!(hit, Some(WatchpointHit { cell: 1, value: 4 }));
+
+        assert_eq!(stepper.run_to_watchpoint().expect("Execution failed"), None);
+        assert_eq!(stepper.machine().tape(), [0, 0]);
+    }
+
+    #[test]
+    fn yield_output() {
+        let mut machine = MachineState::sandboxed();
+        let program = AST::from_string(String::from("++++++[>++++++++<-]>+.+.[-]."));
+
+        let output: Vec<u8> = machine
+            .run_yielding(&program)
+            .collect::<io::Result<_>>()
+            .expect("Execution failed");
+
+        assert_eq!(output, vec![0x31, 0x32, 0x00]);
+    }
+}
@@ -0,0 +1,133 @@
+use super::machine::{MachineState, Operation};
+use std::io::{self, prelude::*};
+
+/// Commands of a loop, with the position of the matching bracket of each bracket.
+struct LoopCode {
+    commands: Vec<u8>,
+    jumps: Vec<usize>,
+}
+
+impl LoopCode {
+    fn new(commands: Vec<u8>) -> LoopCode {
+        let mut jumps = vec![0; commands.len()];
+        let mut opened = Vec::new();
+        for (position, command) in commands.iter().enumerate() {
+            match command {
+                b'[' => opened.push(position),
+                b']' => {
+                    let open = opened.pop().expect("loop code is balanced");
+                    jumps[open] = position;
+                    jumps[position] = open;
+                }
+                _ => {}
+            }
+        }
+        LoopCode { commands, jumps }
+    }
+}
+
+impl MachineState {
+    /// Parses and runs `source` in a single pass, like `run` on the program parsed by
+    /// [`AST::from`](super::ast::AST::from). Only the loop being executed is kept in memory:
+    /// loops skipped on a zero cell are never stored.
+    pub fn run_streaming<R: Read>(&mut self, source: R) -> io::Result<&[u8]> {
+        let mut bytes = io::BufReader::new(source).bytes();
+
+        while let Some(byte) = bytes.next() {
+            match byte? {
+                b'[' => {
+                    let run = self.get_current() != 0;
+                    let mut commands = vec![b'['];
+                    let mut depth = 1;
+                    while depth > 0 {
+                        let byte = match bytes.next() {
+                            Some(byte) => byte?,
+                            None => break,
+                        };
+                        match byte {
+                            b'[' => depth += 1,
+                            b']' => depth -= 1,
+                            _ => {}
+                        }
+                        if run
+                            && (byte == b'['
+                                || byte == b']'
+                                || Operation::from(byte as char).is_some())
+                        {
+                            commands.push(byte);
+                        }
+                    }
+
+                    if run {
+                        // Loops left open at the end of the source are closed there.
+                        commands.extend(std::iter::repeat_n(b']', depth));
+                        self.run_loop(&LoopCode::new(commands))?;
+                    }
+                }
+                b']' => break,
+                byte => {
+                    if let Some(operation) = Operation::from(byte as char) {
+                        self.apply(&operation)?;
+                    }
+                }
+            }
+        }
+
+        Ok(self.tape())
+    }
+
+    fn run_loop(&mut self, code: &LoopCode) -> io::Result<()> {
+        let mut counter = 0;
+        while let Some(&command) = code.commands.get(counter) {
+            match command {
+                b'[' if self.get_current() == 0 => counter = code.jumps[counter],
+                b']' if self.get_current() != 0 => counter = code.jumps[counter],
+                b'[' | b']' => {}
+                _ => {
+                    if let Some(operation) = Operation::from(command as char) {
+                        self.apply(&operation)?;
+                    }
+                }
+            }
+            counter += 1;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::ast::AST;
+    use super::super::output::OutputBuffer;
+    use super::*;
+
+    fn compare(source: &str) {
+        let output = OutputBuffer::new();
+        let mut machine = MachineState::sandboxed().with_output(output.clone());
+        let expected = machine
+            .run(&AST::from_string(String::from(source)))
+            .unwrap()
+            .to_vec();
+
+        let streamed = OutputBuffer::new();
+        let mut machine = MachineState::sandboxed().with_output(streamed.clone());
+        let memory = machine.run_streaming(source.as_bytes()).unwrap();
+
+        assert_eq!(memory, expected.as_slice(), "tape of {:?}", source);
+        assert_eq!(
+            streamed.contents(),
+            output.contents(),
+            "output of {:?}",
+            source
+        );
+    }
+
+    #[test]
+    fn same_as_run() {
+        compare(&std::fs::read_to_string("tests/hello_world.bf").unwrap());
+        compare("[skipped [.] loop]+++[>++[>+<-]<-]>>.");
+        compare("++[>+<-]>]+++");
+        compare("+++[>+<-[>>+<<-");
+        compare("");
+    }
+}
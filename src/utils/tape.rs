@@ -0,0 +1,198 @@
+use std::ops::{Index, IndexMut};
+
+/// Storage for the cells of a machine.
+pub trait Tape: IndexMut<usize, Output = u8> {
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Extends the tape with zero cells up to `len` cells. Fixed size tapes stay untouched.
+    fn grow(&mut self, len: usize);
+
+    fn as_slice(&self) -> &[u8];
+
+    /// Drops the first `cells` cells, returning whether the tape supports it. Fixed size tapes
+    /// don't, and stay untouched.
+    fn drop_front(&mut self, _cells: usize) -> bool {
+        false
+    }
+}
+
+impl Tape for Vec<u8> {
+    fn len(&self) -> usize {
+        Vec::len(self)
+    }
+
+    fn grow(&mut self, len: usize) {
+        if len > Vec::len(self) {
+            self.resize(len, 0u8);
+        }
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        self
+    }
+
+    fn drop_front(&mut self, cells: usize) -> bool {
+        self.drain(..cells);
+        true
+    }
+}
+
+/// Fixed size tape, holding as many cells as it was created with.
+pub(crate) struct FixedTape(pub(crate) Box<[u8]>);
+
+impl Index<usize> for FixedTape {
+    type Output = u8;
+
+    fn index(&self, index: usize) -> &u8 {
+        &self.0[index]
+    }
+}
+
+impl IndexMut<usize> for FixedTape {
+    fn index_mut(&mut self, index: usize) -> &mut u8 {
+        &mut self.0[index]
+    }
+}
+
+impl Tape for FixedTape {
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn grow(&mut self, _len: usize) {}
+
+    fn as_slice(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// Tape of new machines. With the `smallvec` feature, its first cells are stored inline so that
+/// short programs don't allocate for their tape.
+#[cfg(feature = "smallvec")]
+pub(crate) type DefaultTape = smallvec::SmallVec<[u8; 32]>;
+#[cfg(not(feature = "smallvec"))]
+pub(crate) type DefaultTape = Vec<u8>;
+
+#[cfg(feature = "smallvec")]
+impl Tape for smallvec::SmallVec<[u8; 32]> {
+    fn len(&self) -> usize {
+        smallvec::SmallVec::len(self)
+    }
+
+    fn grow(&mut self, len: usize) {
+        if len > smallvec::SmallVec::len(self) {
+            self.resize(len, 0u8);
+        }
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        self
+    }
+
+    fn drop_front(&mut self, cells: usize) -> bool {
+        self.drain(..cells);
+        true
+    }
+}
+
+#[cfg(feature = "mmap")]
+pub use self::mmap::MmapTape;
+
+#[cfg(feature = "mmap")]
+mod mmap {
+    use super::Tape;
+    use memmap2::MmapMut;
+    use std::fs::OpenOptions;
+    use std::io;
+    use std::ops::{Index, IndexMut};
+    use std::path::Path;
+
+    /// Fixed size tape backed by a memory-mapped file.
+    pub struct MmapTape {
+        map: MmapMut,
+    }
+
+    impl MmapTape {
+        /// Maps the first `size` bytes of the file at `path`, creating or extending it as needed.
+        pub fn open<P: AsRef<Path>>(path: P, size: usize) -> io::Result<MmapTape> {
+            if size == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "tape needs at least one cell",
+                ));
+            }
+
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)?;
+            if file.metadata()?.len() < size as u64 {
+                file.set_len(size as u64)?;
+            }
+
+            // SAFETY: the mapping is only sound as long as no one else truncates the file while
+            // the tape is alive, which we cannot prevent and leave to the user.
+            let map = unsafe { memmap2::MmapOptions::new().len(size).map_mut(&file)? };
+            Ok(MmapTape { map })
+        }
+
+        pub fn flush(&self) -> io::Result<()> {
+            self.map.flush()
+        }
+    }
+
+    impl Index<usize> for MmapTape {
+        type Output = u8;
+
+        fn index(&self, index: usize) -> &u8 {
+            &self.map[index]
+        }
+    }
+
+    impl IndexMut<usize> for MmapTape {
+        fn index_mut(&mut self, index: usize) -> &mut u8 {
+            &mut self.map[index]
+        }
+    }
+
+    impl Tape for MmapTape {
+        fn len(&self) -> usize {
+            self.map.len()
+        }
+
+        fn grow(&mut self, _len: usize) {}
+
+        fn as_slice(&self) -> &[u8] {
+            &self.map
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_tape_growth() {
+        let mut tape = DefaultTape::from(&[0][..]);
+        Tape::grow(&mut tape, 20);
+        tape[19] = 3;
+        Tape::grow(&mut tape, 10);
+
+        assert_eq!(Tape::len(&tape), 20);
+        assert_eq!(Tape::as_slice(&tape)[19], 3);
+        #[cfg(feature = "smallvec")]
+        assert!(!tape.spilled());
+
+        Tape::grow(&mut tape, 100);
+        assert_eq!(Tape::len(&tape), 100);
+        #[cfg(feature = "smallvec")]
+        assert!(tape.spilled());
+    }
+}
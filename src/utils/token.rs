@@ -0,0 +1,80 @@
+use super::machine::Operation;
+use std::ops::Range;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TokenKind {
+    Operation(Operation),
+    LoopStart,
+    LoopEnd,
+    /// Run of characters that are not commands.
+    Comment,
+}
+
+/// Piece of source, as given by [`tokenize`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct Token {
+    pub kind: TokenKind,
+    /// Byte offsets of the token in the source.
+    pub span: Range<usize>,
+}
+
+/// Splits `source` into commands and comments, without checking that brackets are balanced.
+/// Every byte of the source belongs to exactly one token.
+pub fn tokenize(source: &str) -> Vec<Token> {
+    let mut tokens: Vec<Token> = Vec::new();
+
+    for (offset, c) in source.char_indices() {
+        let span = offset..offset + c.len_utf8();
+        let kind = match c {
+            '[' => TokenKind::LoopStart,
+            ']' => TokenKind::LoopEnd,
+            _ => match Operation::from(c) {
+                Some(operation) => TokenKind::Operation(operation),
+                None => TokenKind::Comment,
+            },
+        };
+
+        match tokens.last_mut() {
+            Some(last) if kind == TokenKind::Comment && last.kind == TokenKind::Comment => {
+                last.span.end = span.end;
+            }
+            _ => tokens.push(Token { kind, span }),
+        }
+    }
+
+    tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::machine::{Computation, Direction};
+    use super::*;
+
+    #[test]
+    fn tokens() {
+        let tokens: Vec<(TokenKind, Range<usize>)> = tokenize("+[é >]. ok")
+            .into_iter()
+            .map(|token| (token.kind, token.span))
+            .collect();
+
+        assert_eq!(
+            tokens,
+            vec![
+                (
+                    TokenKind::Operation(Operation::Change(Computation::Add)),
+                    0..1
+                ),
+                (TokenKind::LoopStart, 1..2),
+                (TokenKind::Comment, 2..5),
+                (
+                    TokenKind::Operation(Operation::Move(Direction::Right)),
+                    5..6
+                ),
+                (TokenKind::LoopEnd, 6..7),
+                (TokenKind::Operation(Operation::Print), 7..8),
+                (TokenKind::Comment, 8..11),
+            ]
+        );
+        assert!(tokenize("").is_empty());
+    }
+}
@@ -0,0 +1,36 @@
+/// Byte exchanged by a program with the outside world.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IoEvent {
+    /// Cell value printed by `Print`, before any output formatting.
+    Output(u8),
+    /// Byte consumed by `Read`.
+    Input(u8),
+}
+
+/// Input and output of a run, in the order they happened.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Transcript {
+    pub events: Vec<IoEvent>,
+}
+
+impl Transcript {
+    pub fn outputs(&self) -> Vec<u8> {
+        self.events
+            .iter()
+            .filter_map(|event| match event {
+                IoEvent::Output(byte) => Some(*byte),
+                IoEvent::Input(_) => None,
+            })
+            .collect()
+    }
+
+    pub fn inputs(&self) -> Vec<u8> {
+        self.events
+            .iter()
+            .filter_map(|event| match event {
+                IoEvent::Input(byte) => Some(*byte),
+                IoEvent::Output(_) => None,
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,65 @@
+use super::ast::AST;
+use super::machine::Operation;
+
+/// Callbacks for a traversal of a program in source order, driven by [`walk`](Visitor::walk).
+pub trait Visitor {
+    fn visit_op(&mut self, _operation: &Operation) {}
+
+    /// Called before the body of a loop.
+    fn visit_loop_enter(&mut self, _body: &AST) {}
+
+    /// Called after the body of a loop.
+    fn visit_loop_exit(&mut self, _body: &AST) {}
+
+    fn walk(&mut self, mut ast: &AST) {
+        loop {
+            match ast {
+                AST::Instructions(operations, next) => {
+                    for operation in operations {
+                        self.visit_op(operation);
+                    }
+                    ast = next;
+                }
+                AST::Loop(body, next) => {
+                    self.visit_loop_enter(body);
+                    self.walk(body);
+                    self.visit_loop_exit(body);
+                    ast = next;
+                }
+                AST::EOF => return,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct Source(String);
+
+    impl Visitor for Source {
+        fn visit_op(&mut self, operation: &Operation) {
+            self.0 += &operation.to_string();
+        }
+
+        fn visit_loop_enter(&mut self, _body: &AST) {
+            self.0.push('[');
+        }
+
+        fn visit_loop_exit(&mut self, _body: &AST) {
+            self.0.push(']');
+        }
+    }
+
+    #[test]
+    fn rebuild_source() {
+        let program = AST::from_string(String::from("+[>[-]<,[.]]>"));
+        let mut source = Source::default();
+
+        source.walk(&program);
+
+        assert_eq!(source.0, program.to_string());
+    }
+}
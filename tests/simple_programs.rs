@@ -1,4 +1,4 @@
-use rebf::{MachineState, AST};
+use rebf::{assert_output, eval, format_source, pipeline, MachineState, AST};
 use std::fs;
 
 #[test]
@@ -16,3 +16,52 @@ fn hello_world() {
         assert_eq!(memory[i], checked[i]);
     }
 }
+
+#[test]
+fn hello_world_output() {
+    let source = fs::read_to_string("tests/hello_world.bf").expect("File not found.");
+
+    assert_output(&source, "", "Hello World!\n");
+}
+
+#[test]
+#[should_panic(expected = "unexpected output")]
+fn wrong_output() {
+    assert_output("+++++++++[>+++++<-]>.", "", "a");
+}
+
+#[test]
+fn echo_input() {
+    let output = eval(",[.,]", "Hello, échos!").expect("An error occured");
+
+    assert_eq!(output, "Hello, échos!");
+}
+
+#[test]
+fn chained_filters() {
+    let increment = AST::from_string(String::from(",[+.,]"));
+    let reverse = AST::from_string(String::from(">,[>,]<[.<]"));
+
+    let output = pipeline(&[&increment, &reverse], b"HAL").expect("An error occured");
+    assert_eq!(output, b"MBI");
+
+    assert_eq!(pipeline(&[], b"abc").unwrap(), b"abc");
+}
+
+#[test]
+fn formatted_source() {
+    let source = fs::read_to_string("tests/hello_world.bf").expect("File not found.");
+    let formatted = format_source(&source).expect("Invalid program");
+
+    assert_output(&formatted, "", "Hello World!\n");
+    assert_eq!(format_source(&formatted).unwrap(), formatted);
+    assert!(format_source("+[>+").is_err());
+}
+
+#[test]
+fn generated_text() {
+    for text in ["", "Hello World!\n", "two\nlines\r\n", "été ∑ 🦀"] {
+        let output = eval(&AST::print_str(text).to_string(), "").expect("An error occured");
+        assert_eq!(output, text);
+    }
+}